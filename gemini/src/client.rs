@@ -1,19 +1,36 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::rc::Rc;
 
 use futures::io::Cursor;
 use futures::prelude::*;
-use futures::task::{Context, Poll};
-use gio::prelude::*;
 use log::debug;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use url::Url;
 
+use crate::identity::IdentityProvider;
+use crate::mime::{self, Mime};
+use crate::proxy::Proxy;
+use crate::transport::{Connection, Endpoint, TcpTransport, Tls, Transport};
 use crate::{known_hosts, CertificateError};
 
 const MAX_REDIRECT: u8 = 5;
-// Timeout measured in seconds
-const MAX_TIMEOUT_SECONDS: u32 = 10;
+const MAX_META_LEN: usize = 1024;
+// Characters of a titan parameter value that would end the value or the path
+const TITAN_PARAM: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b';')
+    .add(b'<')
+    .add(b'=')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
 
 #[derive(Debug, thiserror::Error)]
 pub enum ProtoError {
@@ -39,12 +56,20 @@ pub enum Error {
     InvalidUtf8(#[from] std::str::Utf8Error),
     #[error("Invalid url: {0:?}")]
     InvalidUrl(#[from] url::ParseError),
-    #[error("Tls error: {0:?}")]
-    Tls(#[from] CertificateError),
+    /// Certificate refused for the server at `host:port`, which is the proxy for the proxied
+    /// urls
+    #[error("Tls error from {host}:{port}: {error:?}")]
+    Tls {
+        host: String,
+        port: u16,
+        error: CertificateError,
+    },
     #[error("Invalid host")]
     InvalidHost,
     #[error("Too many redirections. Last requested redirect was {0}")]
     TooManyRedirects(String),
+    #[error("Refused to follow the redirect to {0}")]
+    RedirectRefused(String),
     #[error("This library only support the gemini url scheme")]
     SchemeNotSupported,
 }
@@ -58,6 +83,22 @@ pub enum Status {
     PermFail(u8),
     CertRequired(u8),
 }
+impl Status {
+    /// Raw two digits code
+    pub fn raw(&self) -> u8 {
+        match *self {
+            Status::Input(s)
+            | Status::Success(s)
+            | Status::Redirect(s)
+            | Status::TempFail(s)
+            | Status::PermFail(s)
+            | Status::CertRequired(s) => s,
+        }
+    }
+    pub fn code(&self) -> StatusCode {
+        StatusCode::from(self.raw())
+    }
+}
 impl TryFrom<u8> for Status {
     type Error = InvalidStatus;
     fn try_from(s: u8) -> Result<Self, Self::Error> {
@@ -73,76 +114,193 @@ impl TryFrom<u8> for Status {
     }
 }
 
+/// Meaning of the status codes defined by the specification
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatusCode {
+    Input,
+    SensitiveInput,
+    Success,
+    TemporaryRedirect,
+    PermanentRedirect,
+    TemporaryFailure,
+    ServerUnavailable,
+    CgiError,
+    ProxyError,
+    /// The meta contains the number of seconds to wait before retrying
+    SlowDown,
+    PermanentFailure,
+    NotFound,
+    Gone,
+    ProxyRequestRefused,
+    BadRequest,
+    CertificateRequired,
+    CertificateNotAuthorized,
+    CertificateNotValid,
+    /// Code without a specific meaning. It must be handled like the first code of its group
+    Other(u8),
+}
+impl From<u8> for StatusCode {
+    fn from(s: u8) -> Self {
+        match s {
+            10 => StatusCode::Input,
+            11 => StatusCode::SensitiveInput,
+            20 => StatusCode::Success,
+            30 => StatusCode::TemporaryRedirect,
+            31 => StatusCode::PermanentRedirect,
+            40 => StatusCode::TemporaryFailure,
+            41 => StatusCode::ServerUnavailable,
+            42 => StatusCode::CgiError,
+            43 => StatusCode::ProxyError,
+            44 => StatusCode::SlowDown,
+            50 => StatusCode::PermanentFailure,
+            51 => StatusCode::NotFound,
+            52 => StatusCode::Gone,
+            53 => StatusCode::ProxyRequestRefused,
+            59 => StatusCode::BadRequest,
+            60 => StatusCode::CertificateRequired,
+            61 => StatusCode::CertificateNotAuthorized,
+            62 => StatusCode::CertificateNotValid,
+            s => StatusCode::Other(s),
+        }
+    }
+}
+
+/// Validates the certificate presented by the server at `host:port`. `errors` are the errors
+/// found by checking it against the system trust store, empty if it's signed by a trusted
+/// authority
 pub trait Validator {
-    fn validate(&mut self, host: &str, cert: &gio::TlsCertificate) -> Result<(), CertificateError>;
+    fn validate(
+        &mut self,
+        host: &str,
+        port: u16,
+        cert: &gio::TlsCertificate,
+        errors: gio::TlsCertificateFlags,
+    ) -> Result<(), CertificateError>;
 }
 
-impl<F: FnMut(&str, &gio::TlsCertificate) -> Result<(), CertificateError>> Validator for F {
-    fn validate(&mut self, host: &str, cert: &gio::TlsCertificate) -> Result<(), CertificateError> {
-        self(host, cert)
+impl<
+        F: FnMut(
+            &str,
+            u16,
+            &gio::TlsCertificate,
+            gio::TlsCertificateFlags,
+        ) -> Result<(), CertificateError>,
+    > Validator for F
+{
+    fn validate(
+        &mut self,
+        host: &str,
+        port: u16,
+        cert: &gio::TlsCertificate,
+        errors: gio::TlsCertificateFlags,
+    ) -> Result<(), CertificateError> {
+        self(host, port, cert, errors)
     }
 }
 
-pub struct ConnectionAsyncRead<T: AsyncRead> {
-    // WARNING: The connection MUST STAY IN SCOPE while the body is read. If the connection
-    // goes out of scope, it gets closed and reading it becomes impossible.
-    pub connection: gio::SocketConnection,
-    pub readable: T,
+/// Writes `request` to the connection, returning it to read the response
+pub(crate) async fn send_request(
+    mut connection: Box<dyn Connection>,
+    request: Vec<u8>,
+) -> Result<Box<dyn Connection>, Error> {
+    connection.write_all(&request).await?;
+    connection.flush().await?;
+    Ok(connection)
 }
-fn suppress_tls_connection_closed_error(
-    res: Result<usize, std::io::Error>,
-) -> Result<usize, std::io::Error> {
-    res.or_else(|e| {
-        if e.kind() == std::io::ErrorKind::Other {
-            let inner = e.into_inner().map(|e| e.downcast()).unwrap();
-            inner
-                .and_then(|gio_err: Box<glib::error::Error>| {
-                    match gio_err.kind::<gio::TlsError>() {
-                        // map the error to an equivalent read of 0 bytes, which will signal the end of the
-                        // connection
-                        Some(gio::TlsError::Eof) => {
-                            debug!("suppressed gio tls eof error");
-                            Ok(0)
-                        }
-                        _ => Err(gio_err.into()),
-                    }
-                })
-                .or_else(|e| Err(std::io::Error::other(e)))
-        } else {
-            Err(e)
-        }
-    })
-}
-impl<T: AsyncRead + std::marker::Unpin> AsyncRead for ConnectionAsyncRead<T> {
-    // Required method
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut [u8],
-    ) -> Poll<Result<usize, std::io::Error>> {
-        let readable = &mut self.as_mut().readable;
-        futures::pin_mut!(readable);
-        let readable: std::pin::Pin<_> = readable.as_mut();
-        let res = AsyncRead::poll_read(readable, cx, buf);
-        res.map(|ready| suppress_tls_connection_closed_error(ready))
+
+/// Header of a response, framed as expected by [`Response::from_async_read`]. Returns `None`
+/// if the meta is too long
+pub(crate) fn response_header(status: Status, meta: &str) -> Option<Vec<u8>> {
+    if meta.len() > MAX_META_LEN {
+        return None;
     }
+    Some(format!("{:02} {}\r\n", status.raw(), meta).into_bytes())
+}
+
+/// Host and port of `url`, in the form expected by [`Endpoint`]
+fn host_port(url: &Url, default_port: u16) -> Result<(String, u16), Error> {
+    let host = match url.host() {
+        Some(url::Host::Domain(domain)) => domain.to_owned(),
+        Some(url::Host::Ipv4(ip)) => ip.to_string(),
+        Some(url::Host::Ipv6(ip)) => ip.to_string(),
+        None => return Err(Error::InvalidHost),
+    };
+    Ok((host, url.port().unwrap_or(default_port)))
+}
+
+/// A redirect followed before getting the final response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectHop {
+    /// Requested url
+    pub url: Url,
+    /// Redirect status sent by the server
+    pub status: Status,
+    /// Url the server redirected to
+    pub target: Url,
 }
 
 pub struct Response {
     status: Status,
     meta: String,
     body: Box<dyn AsyncRead + std::marker::Unpin>,
+    redirects: Vec<RedirectHop>,
+    certificate: Option<gio::TlsCertificate>,
 }
 impl Response {
+    pub(crate) fn new(
+        status: Status,
+        meta: String,
+        body: Box<dyn AsyncRead + std::marker::Unpin>,
+    ) -> Self {
+        Self {
+            status,
+            meta,
+            body,
+            redirects: vec![],
+            certificate: None,
+        }
+    }
     pub fn status(&self) -> Status {
         self.status
     }
     pub fn meta(&self) -> &str {
         &self.meta
     }
+    /// Time to wait before retrying, when the server answered with [`StatusCode::SlowDown`]
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self.status.code() {
+            StatusCode::SlowDown => self
+                .meta
+                .trim()
+                .parse()
+                .ok()
+                .map(std::time::Duration::from_secs),
+            _ => None,
+        }
+    }
+    /// Redirects followed to get this response, from the first one
+    pub fn redirects(&self) -> &[RedirectHop] {
+        &self.redirects
+    }
+    pub(crate) fn with_redirects(mut self, redirects: Vec<RedirectHop>) -> Self {
+        self.redirects = redirects;
+        self
+    }
+    /// Certificate presented by the server, for the responses received over tls
+    pub fn certificate(&self) -> Option<&gio::TlsCertificate> {
+        self.certificate.as_ref()
+    }
+    pub(crate) fn with_certificate(mut self, certificate: Option<gio::TlsCertificate>) -> Self {
+        self.certificate = certificate;
+        self
+    }
     pub fn meta_owned(self) -> String {
         self.meta
     }
+    /// Parsed mime type, if the response is a success
+    pub fn mime(&self) -> Option<Mime> {
+        mime::from_meta(self.status, &self.meta)
+    }
     pub fn body(self) -> Option<impl AsyncRead> {
         match self.status {
             Status::Success(_) => Some(self.body),
@@ -152,49 +310,132 @@ impl Response {
     pub async fn from_async_read(
         mut async_readable: impl AsyncRead + std::marker::Unpin + 'static,
     ) -> Result<Self, Error> {
-        let mut buffer = Vec::with_capacity(2048);
-        // 3 bytes for the status, 1024 max bytes for the meta
-        (&mut async_readable)
-            .take(3 + 1024)
-            .read_to_end(&mut buffer)
-            .await?;
-
-        let meta_end = buffer[3..]
-            .windows(2)
-            .position(|w| w == b"\r\n")
-            .map(|i| i + 3)
-            .ok_or(Error::InvalidProtocolData(ProtoError::MetaNotFound))?;
-
-        let status: u8 = std::str::from_utf8(buffer.get(0..2).unwrap_or(&[]))?
-            .parse()
-            .map_err(|_| Error::InvalidProtocolData(InvalidStatus.into()))?;
-
-        let status = Status::try_from(status)
-            .map_err(|_| Error::InvalidProtocolData(InvalidStatus.into()))?;
-
-        let meta_buffer = &buffer.get(3..meta_end).unwrap_or(&[]);
-        // Split the part of the buffer containing the meta
-        let meta = String::from_utf8_lossy(meta_buffer).to_string();
-
-        // 2b offset for '\r\n'
-        let split_at = meta_end + 2;
-        let cursor = Cursor::new(buffer.split_off(split_at));
-        Ok(Response {
+        let (status, meta, rest) = read_header(&mut async_readable).await?;
+        let cursor = Cursor::new(rest);
+        Ok(Response::new(
             status,
             meta,
-            body: Box::new(cursor.chain(async_readable)),
-        })
+            Box::new(cursor.chain(async_readable)),
+        ))
     }
 }
+
+/// Reads the header of a response, returning its status, its meta and the part of the body
+/// read with it
+pub(crate) async fn read_header(
+    async_readable: &mut (impl AsyncRead + std::marker::Unpin),
+) -> Result<(Status, String, Vec<u8>), Error> {
+    let mut buffer = Vec::with_capacity(2048);
+    // 3 bytes for the status, 1024 max bytes for the meta
+    (&mut *async_readable)
+        .take(3 + MAX_META_LEN as u64)
+        .read_to_end(&mut buffer)
+        .await?;
+
+    let meta_end = buffer[3..]
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|i| i + 3)
+        .ok_or(Error::InvalidProtocolData(ProtoError::MetaNotFound))?;
+
+    let status: u8 = std::str::from_utf8(buffer.get(0..2).unwrap_or(&[]))?
+        .parse()
+        .map_err(|_| Error::InvalidProtocolData(InvalidStatus.into()))?;
+
+    let status =
+        Status::try_from(status).map_err(|_| Error::InvalidProtocolData(InvalidStatus.into()))?;
+
+    let meta_buffer = &buffer.get(3..meta_end).unwrap_or(&[]);
+    // Split the part of the buffer containing the meta
+    let meta = String::from_utf8_lossy(meta_buffer).to_string();
+
+    // 2b offset for '\r\n'
+    let split_at = meta_end + 2;
+    Ok((status, meta, buffer.split_off(split_at)))
+}
+
+/// What to do with a redirect leaving the current host or scheme
 #[derive(Default, PartialEq, Eq, Debug, Copy, Clone)]
+pub enum RedirectPolicy {
+    /// Follow it like any other redirect
+    #[default]
+    Follow,
+    /// Return the redirect response, letting the caller decide
+    Stop,
+    /// Fail with [`Error::RedirectRefused`]
+    Refuse,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub struct ClientOptions {
-    redirect: bool,
+    pub(crate) redirect: bool,
+    pub(crate) max_redirects: u8,
+    pub(crate) cross_host: RedirectPolicy,
+    pub(crate) cross_scheme: RedirectPolicy,
+}
+
+impl ClientOptions {
+    /// Url to request after receiving `status` and `meta` from `url`, if it's a redirect that
+    /// must be followed. The followed redirect is appended to `redirects`
+    pub(crate) fn next_hop(
+        &self,
+        url: &Url,
+        status: Status,
+        meta: &str,
+        redirects: &mut Vec<RedirectHop>,
+    ) -> Result<Option<Url>, Error> {
+        let Status::Redirect(_) = status else {
+            return Ok(None);
+        };
+        if !self.redirect {
+            return Ok(None);
+        }
+        let target = url.join(meta)?;
+
+        let policy = if target.scheme() != url.scheme() {
+            self.cross_scheme
+        } else if target.host() != url.host() || target.port() != url.port() {
+            self.cross_host
+        } else {
+            RedirectPolicy::Follow
+        };
+        match policy {
+            RedirectPolicy::Follow => {}
+            RedirectPolicy::Stop => return Ok(None),
+            RedirectPolicy::Refuse => return Err(Error::RedirectRefused(target.to_string())),
+        }
+
+        if redirects.len() >= self.max_redirects as usize {
+            return Err(Error::TooManyRedirects(target.to_string()));
+        }
+        redirects.push(RedirectHop {
+            url: url.clone(),
+            status,
+            target: target.clone(),
+        });
+        Ok(Some(target))
+    }
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            redirect: false,
+            max_redirects: MAX_REDIRECT,
+            cross_host: RedirectPolicy::default(),
+            cross_scheme: RedirectPolicy::default(),
+        }
+    }
 }
 
 #[derive(Default, Clone)]
 pub struct ClientBuilder {
     options: ClientOptions,
     validator: Option<Rc<RefCell<dyn Validator>>>,
+    identity_provider: Option<Rc<RefCell<dyn IdentityProvider>>>,
+    scheme_proxies: HashMap<String, String>,
+    proxy: Option<Proxy>,
+    transport: Option<Rc<dyn Transport>>,
 }
 
 impl std::fmt::Debug for ClientBuilder {
@@ -202,6 +443,10 @@ impl std::fmt::Debug for ClientBuilder {
         f.debug_struct("ClientBuilder")
             .field("options", &self.options)
             .field("validator", &self.validator.is_some())
+            .field("identity_provider", &self.identity_provider.is_some())
+            .field("scheme_proxies", &self.scheme_proxies)
+            .field("proxy", &self.proxy)
+            .field("transport", &self.transport.is_some())
             .finish()
     }
 }
@@ -214,24 +459,71 @@ impl ClientBuilder {
         self.options.redirect = redirect;
         self
     }
+    /// Maximum number of redirects followed by a single request
+    pub fn max_redirects(mut self, max: u8) -> Self {
+        self.options.max_redirects = max;
+        self
+    }
+    /// Policy for the redirects to another host (or port)
+    pub fn cross_host_redirects(mut self, policy: RedirectPolicy) -> Self {
+        self.options.cross_host = policy;
+        self
+    }
+    /// Policy for the redirects to another scheme
+    pub fn cross_scheme_redirects(mut self, policy: RedirectPolicy) -> Self {
+        self.options.cross_scheme = policy;
+        self
+    }
     pub fn validator(mut self, f: impl Validator + 'static) -> Self {
         self.validator = Some(Rc::new(RefCell::new(f)));
         self
     }
+    pub fn identity_provider(mut self, f: impl IdentityProvider + 'static) -> Self {
+        self.identity_provider = Some(Rc::new(RefCell::new(f)));
+        self
+    }
+    /// Sends the requests for `scheme` urls to the gemini proxy at `proxy` (`host[:port]`).
+    /// The proxy receives the absolute url and answers with a normal gemini response
+    pub fn scheme_proxy(mut self, scheme: &str, proxy: &str) -> Self {
+        self.scheme_proxies
+            .insert(scheme.to_owned(), proxy.to_owned());
+        self
+    }
+    /// Opens every connection through a SOCKS5 proxy, such as the one of Tor.
+    /// Only used by the default transport
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+    /// Replaces the default transport, [`TcpTransport`]
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Rc::new(transport));
+        self
+    }
     pub fn build(self) -> Client {
+        let proxy = self.proxy;
         Client {
             options: self.options,
             validator: self
                 .validator
                 .unwrap_or_else(|| Client::default_validator()),
+            identity_provider: self.identity_provider,
+            scheme_proxies: self.scheme_proxies,
+            transport: self.transport.unwrap_or_else(|| match proxy {
+                Some(proxy) => Rc::new(TcpTransport::with_proxy(proxy)),
+                None => Rc::new(TcpTransport::new()),
+            }),
         }
     }
 }
 
 #[derive(Clone)]
 pub struct Client {
-    options: ClientOptions,
+    pub(crate) options: ClientOptions,
     validator: Rc<RefCell<dyn Validator>>,
+    identity_provider: Option<Rc<RefCell<dyn IdentityProvider>>>,
+    scheme_proxies: HashMap<String, String>,
+    transport: Rc<dyn Transport>,
 }
 
 impl Default for Client {
@@ -239,6 +531,9 @@ impl Default for Client {
         Self {
             options: Default::default(),
             validator: Self::default_validator(),
+            identity_provider: None,
+            scheme_proxies: Default::default(),
+            transport: Rc::new(TcpTransport::new()),
         }
     }
 }
@@ -247,6 +542,7 @@ impl std::fmt::Debug for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Client")
             .field("options", &self.options)
+            .field("scheme_proxies", &self.scheme_proxies)
             .finish()
     }
 }
@@ -255,8 +551,15 @@ impl Client {
     pub fn default_validator() -> Rc<RefCell<dyn Validator>> {
         let mut known_hosts = known_hosts::KnownHostsMap::new();
         Rc::new(RefCell::new(
-            move |host: &str, cert: &gio::TlsCertificate| {
-                known_hosts::validate(&mut known_hosts, host, cert)
+            move |host: &str, port: u16, cert: &gio::TlsCertificate, errors| {
+                known_hosts::validate_with_mode(
+                    &mut known_hosts,
+                    host,
+                    port,
+                    cert,
+                    errors,
+                    known_hosts::ValidationMode::default(),
+                )
             },
         ))
     }
@@ -265,112 +568,128 @@ impl Client {
     }
 
     pub async fn fetch(&self, url_str: &str) -> Result<Response, Error> {
-        let mut url_str = url_str.to_string();
-        let mut res;
-        let mut i = 0;
-        let max_redirect = if self.options.redirect {
-            MAX_REDIRECT
-        } else {
-            1
-        };
-        while {
-            let url = Url::parse(&url_str)?;
-            res = self.fetch_internal(url).await?;
-            i < max_redirect
-        } {
-            match res.status() {
-                Status::Redirect(_) => {
-                    if let Err(url::ParseError::RelativeUrlWithoutBase) = Url::parse(res.meta()) {
-                        let base_url = Url::parse(&url_str)?;
-                        let new_url = base_url.join(res.meta())?;
-
-                        url_str = new_url.to_string();
-                    } else {
-                        url_str = res.meta().to_string();
-                    }
-                    i += 1;
-                }
-                _ => break,
+        let mut url = Url::parse(url_str)?;
+        let mut redirects = vec![];
+        loop {
+            let res = self.fetch_internal(url.clone(), None).await?;
+            match self.next_hop(&url, &res, &mut redirects)? {
+                Some(target) => url = target,
+                None => return Ok(res.with_redirects(redirects)),
             }
         }
-        Ok(res)
     }
-    async fn connect(&self, url: Url) -> Result<gio::SocketConnection, Error> {
-        if url.scheme() != "gemini" {
-            return Err(Error::SchemeNotSupported);
-        }
-        let host = url.host_str().ok_or(Error::InvalidHost)?.to_owned();
-        let addr =
-            gio::NetworkAddress::parse_uri(url.as_str(), 1965).map_err(|_| Error::InvalidHost)?;
-        let socket = gio::SocketClient::new();
-        socket.set_tls(true);
-        socket.set_timeout(MAX_TIMEOUT_SECONDS);
-
-        let tls_error = Rc::new(RefCell::new(None));
-        let validator = self.validator.clone();
-        let tls_error_clone = tls_error.clone();
-        socket.connect_event(move |_this, event, _connectable, connection| {
-            use gio::SocketClientEvent;
-            if event == SocketClientEvent::TlsHandshaking {
-                let connection = connection
-                    .as_ref()
-                    .unwrap()
-                    .dynamic_cast_ref::<gio::TlsClientConnection>()
-                    .unwrap();
-
-                let host = host.clone();
-                let validator = validator.clone();
-                let tls_error_clone = tls_error_clone.clone();
-                connection.connect_accept_certificate(move |_this, cert, _cert_flags| {
-                    match validator.borrow_mut().validate(&host, cert) {
-                        Ok(()) => true,
-                        Err(e) => {
-                            tls_error_clone.replace(Some(e));
-                            false
-                        }
-                    }
-                });
-            }
-        });
-
-        // Open the connection, without checking for errors
-        let iostream = socket.connect_future(&addr).await;
-
-        // Handle the custom tls errors, before handling the automatic iostream errors
-        if let Some(e) = tls_error.borrow().as_ref() {
-            return Err(Error::Tls(*e));
+    /// Url to request after receiving `res` from `url`, if `res` is a redirect that must be
+    /// followed. The followed redirect is appended to `redirects`
+    pub(crate) fn next_hop(
+        &self,
+        url: &Url,
+        res: &Response,
+        redirects: &mut Vec<RedirectHop>,
+    ) -> Result<Option<Url>, Error> {
+        self.options
+            .next_hop(url, res.status(), res.meta(), redirects)
+    }
+    /// Uploads `body` to a titan url. The response isn't followed, even if it's a redirect
+    pub async fn upload(
+        &self,
+        url_str: &str,
+        mime: &str,
+        token: Option<&str>,
+        body: &[u8],
+    ) -> Result<Response, Error> {
+        let url = titan_request_url(Url::parse(url_str)?, mime, body.len(), token)?;
+        self.fetch_internal(url, Some(body)).await
+    }
+    /// Opens a tls connection for `url`, returning it with the certificate of the server
+    async fn connect(
+        &self,
+        url: &Url,
+    ) -> Result<(Box<dyn Connection>, Option<gio::TlsCertificate>), Error> {
+        let (host, port) = match self.scheme_proxies.get(url.scheme()) {
+            Some(proxy) => Url::parse(&format!("gemini://{}", proxy))
+                .map_err(|_| Error::InvalidHost)
+                .and_then(|proxy| host_port(&proxy, 1965))?,
+            None if ["gemini", "titan"].contains(&url.scheme()) => host_port(url, 1965)?,
+            None => return Err(Error::SchemeNotSupported),
         };
-
-        iostream.map_err(|e| Error::Gio(e.to_string()))
+        let identity = self
+            .identity_provider
+            .as_ref()
+            .and_then(|provider| provider.borrow_mut().identity_for(url));
+        let peer_certificate = Rc::new(RefCell::new(None));
+        let endpoint = Endpoint {
+            host,
+            port,
+            tls: Some(Tls {
+                validator: self.validator.clone(),
+                identity,
+                peer_certificate: peer_certificate.clone(),
+            }),
+        };
+        let connection = self.transport.connect(endpoint).await?;
+        Ok((connection, peer_certificate.take()))
+    }
+    /// Opens a plain tcp connection, for the protocols not using tls
+    pub(crate) async fn connect_plain(
+        &self,
+        url: &Url,
+        default_port: u16,
+    ) -> Result<Box<dyn Connection>, Error> {
+        let (host, port) = host_port(url, default_port)?;
+        let endpoint = Endpoint {
+            host,
+            port,
+            tls: None,
+        };
+        self.transport.connect(endpoint).await
     }
-    async fn fetch_internal(&self, url: Url) -> Result<Response, Error> {
-        let connection = self.connect(url.clone()).await?;
-        let url_request = url.to_string() + "\r\n";
-        connection
-            .output_stream()
-            .write_all_future(url_request.into_bytes(), glib::Priority::default())
-            .await
-            .map_err(|(_, e)| Error::Gio(e.to_string()))?;
+    async fn fetch_internal(&self, url: Url, body: Option<&[u8]>) -> Result<Response, Error> {
+        let (connection, certificate) = self.connect(&url).await?;
+        let mut request = (url.to_string() + "\r\n").into_bytes();
+        if let Some(body) = body {
+            request.extend_from_slice(body);
+        }
+        let async_readable = send_request(connection, request).await?;
         debug!("Request sent at {}", url);
 
-        let readable = connection
-            .input_stream()
-            .dynamic_cast::<gio::PollableInputStream>()
-            .unwrap()
-            .into_async_buf_read(1024);
+        let res = Response::from_async_read(async_readable).await?;
+        Ok(res.with_certificate(certificate))
+    }
+}
 
-        let async_readable = ConnectionAsyncRead {
-            connection,
-            readable,
-        };
-        Response::from_async_read(async_readable).await
+/// Appends the titan parameters to the path of `url`, as in `titan://host/path;mime=...;size=...`
+fn titan_request_url(
+    mut url: Url,
+    mime: &str,
+    size: usize,
+    token: Option<&str>,
+) -> Result<Url, Error> {
+    if url.scheme() != "titan" {
+        return Err(Error::SchemeNotSupported);
+    }
+    let mut path = format!(
+        "{};mime={};size={}",
+        url.path(),
+        utf8_percent_encode(mime, TITAN_PARAM),
+        size
+    );
+    if let Some(token) = token {
+        path.push_str(";token=");
+        path.extend(utf8_percent_encode(token, TITAN_PARAM));
     }
+    url.set_path(&path);
+    Ok(url)
 }
 
 #[cfg(test)]
 mod tests {
     use std::future::Future;
 
+    use futures::prelude::*;
+    use url::Url;
+
+    use super::titan_request_url;
+    use crate::transport::MemoryTransport;
     use crate::*;
 
     fn block_on<T>(f: impl Future<Output = T>) -> T {
@@ -387,7 +706,13 @@ mod tests {
     #[test]
     fn client_builder() {
         let client = ClientBuilder::new().redirect(true).build();
-        assert_eq!(client.options, ClientOptions { redirect: true });
+        assert_eq!(
+            client.options,
+            ClientOptions {
+                redirect: true,
+                ..Default::default()
+            }
+        );
     }
 
     #[test]
@@ -397,6 +722,14 @@ mod tests {
             Basic example response from a dummy server",
         )?;
         assert_eq!(res.status(), Status::Success(20));
+        assert!(res.mime().unwrap().is_gemini());
+
+        let res = response_from_bytes(b"20 application/x-text-foo; charset=koi8-r\r\n")?;
+        let mime = res.mime().unwrap();
+        assert_eq!(mime.essence(), "application/x-text-foo");
+        assert_eq!(mime.charset(), "koi8-r");
+        assert!(response_from_bytes(b"20 \r\n")?.mime().unwrap().is_gemini());
+        assert!(response_from_bytes(b"51 Not found\r\n")?.mime().is_none());
         Ok(())
     }
 
@@ -444,22 +777,159 @@ mod tests {
         Ok(())
     }
 
+    /// In-memory capsule at `example.org`, answering each request with the matching response
+    fn capsule(responses: &'static [(&'static str, &'static str)]) -> MemoryTransport {
+        let certificate = identity::Identity::generate("example.org")
+            .unwrap()
+            .certificate()
+            .unwrap();
+        MemoryTransport::new().serve(
+            "example.org",
+            1965,
+            Some(certificate),
+            move |stream| async move {
+                let mut stream = futures::io::BufReader::new(stream);
+                let mut request = String::new();
+                stream.read_line(&mut request).await.unwrap();
+                let response = responses
+                    .iter()
+                    .find(|(url, _)| request.trim_end() == *url)
+                    .map_or("51 Not found\r\n", |(_, response)| response);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            },
+        )
+    }
+
     #[test]
     fn home_auto_redirect() -> Result<(), Error> {
         block_on(async {
-            let client = ClientBuilder::new().redirect(true).build();
+            let transport = capsule(&[
+                ("gemini://example.org", "31 gemini://example.org/\r\n"),
+                ("gemini://example.org/", "20 text/gemini\r\n# Home\n"),
+            ]);
+            let client = ClientBuilder::new()
+                .redirect(true)
+                .transport(transport)
+                .build();
 
             // The url doesn't have a final slash. It's going to be redirected to /
-            let res = client.fetch("gemini://gemini.circumlunar.space").await?;
+            let res = client.fetch("gemini://example.org").await?;
 
             assert_eq!(res.status(), Status::Success(20));
             assert_eq!(res.meta(), "text/gemini");
-            assert!(res.body().is_some());
+            assert_eq!(res.redirects().len(), 1);
+            assert!(res.certificate().is_some());
+
+            let mut body = String::new();
+            res.body().unwrap().read_to_string(&mut body).await?;
+            assert_eq!(body, "# Home\n");
 
             Ok(())
         })
     }
 
+    #[test]
+    fn tls_validation_error() -> Result<(), Error> {
+        block_on(async {
+            let client = ClientBuilder::new()
+                .validator(
+                    |_: &str, _: u16, _: &gio::TlsCertificate, errors: gio::TlsCertificateFlags| {
+                        // The capsule certificate is self signed
+                        assert!(errors.contains(gio::TlsCertificateFlags::UNKNOWN_CA));
+                        Err(CertificateError::BadIdentity)
+                    },
+                )
+                .transport(capsule(&[]))
+                .build();
+            let res = client.fetch("gemini://example.org/").await;
+            assert!(matches!(
+                res,
+                Err(Error::Tls {
+                    error: CertificateError::BadIdentity,
+                    ..
+                })
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn status_codes() -> Result<(), Error> {
+        let res = response_from_bytes(b"51 No such page\r\n")?;
+        assert_eq!(res.status().code(), StatusCode::NotFound);
+        assert_eq!(res.meta(), "No such page");
+        assert_eq!(res.retry_after(), None);
+
+        let res = response_from_bytes(b"44 30\r\n")?;
+        assert_eq!(res.status().code(), StatusCode::SlowDown);
+        assert_eq!(res.retry_after(), Some(std::time::Duration::from_secs(30)));
+
+        let res = response_from_bytes(b"57 Unknown\r\n")?;
+        assert_eq!(res.status(), Status::PermFail(57));
+        assert_eq!(res.status().code(), StatusCode::Other(57));
+        Ok(())
+    }
+
+    #[test]
+    fn redirect_policies() -> Result<(), Error> {
+        let url = Url::parse("gemini://example.org/old")?;
+        let client = ClientBuilder::new()
+            .redirect(true)
+            .max_redirects(1)
+            .cross_host_redirects(RedirectPolicy::Stop)
+            .cross_scheme_redirects(RedirectPolicy::Refuse)
+            .build();
+        let mut redirects = vec![];
+
+        let res = response_from_bytes(b"31 gemini://example.com/\r\n")?;
+        assert_eq!(client.next_hop(&url, &res, &mut redirects)?, None);
+        let res = response_from_bytes(b"31 https://example.org/\r\n")?;
+        assert!(matches!(
+            client.next_hop(&url, &res, &mut redirects),
+            Err(Error::RedirectRefused(_))
+        ));
+        assert!(redirects.is_empty());
+
+        let res = response_from_bytes(b"31 /new\r\n")?;
+        let target = client.next_hop(&url, &res, &mut redirects)?;
+        assert_eq!(
+            target.as_ref().map(Url::as_str),
+            Some("gemini://example.org/new")
+        );
+        assert_eq!(redirects[0].url, url);
+        assert_eq!(redirects[0].status, Status::Redirect(31));
+
+        assert!(matches!(
+            client.next_hop(&url, &res, &mut redirects),
+            Err(Error::TooManyRedirects(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn titan_params() -> Result<(), Error> {
+        let url = Url::parse("titan://example.org/capsule/index.gmi?x")?;
+        let url = titan_request_url(url, "text/gemini", 12, Some("secret"))?;
+        assert_eq!(
+            url.as_str(),
+            "titan://example.org/capsule/index.gmi;mime=text/gemini;size=12;token=secret?x"
+        );
+
+        // The values can't add parameters or end the path
+        let url = Url::parse("titan://example.org/a.txt")?;
+        let url = titan_request_url(url, "text/plain; charset=utf-8", 1, Some("a;b=c?d#e f"))?;
+        assert_eq!(
+            url.as_str(),
+            "titan://example.org/a.txt;mime=text/plain%3B%20charset%3Dutf-8;size=1;token=a%3Bb%3Dc%3Fd%23e%20f"
+        );
+        assert_eq!(url.query(), None);
+        assert_eq!(url.fragment(), None);
+
+        let url = Url::parse("gemini://example.org/")?;
+        assert!(titan_request_url(url, "text/gemini", 0, None).is_err());
+        Ok(())
+    }
+
     #[test]
     fn invalid_scheme() -> Result<(), Error> {
         block_on(async {
@@ -469,4 +939,29 @@ mod tests {
             Ok(())
         })
     }
+
+    #[test]
+    fn proxied_scheme() -> Result<(), Error> {
+        block_on(async {
+            // Nothing listens on port 1, but the scheme must be accepted
+            let client = ClientBuilder::new()
+                .scheme_proxy("http", "localhost:1")
+                .build();
+            let res = client.fetch("http://gemini.circumlunar.space").await;
+            assert!(matches!(res, Err(Error::Gio(_))));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn unreachable_socks_proxy() -> Result<(), Error> {
+        block_on(async {
+            let client = ClientBuilder::new()
+                .proxy("socks5h://localhost:1".parse().unwrap())
+                .build();
+            let res = client.fetch("gemini://example.onion/").await;
+            assert!(matches!(res, Err(Error::Gio(_))));
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,201 @@
+//! Minimal DER parser, reading the few fields of an X.509 certificate needed by the
+//! known hosts store
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+const CONTEXT_0: u8 = 0xa0;
+const INTEGER: u8 = 0x02;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// Short names of the attributes shown in a subject, by their encoded OID
+const ATTRIBUTES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x06], "C"),
+];
+
+/// Fields of a parsed certificate, borrowing from its DER encoding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Certificate<'a> {
+    /// Unix time from which the certificate is valid
+    pub not_before: i64,
+    /// Unix time after which the certificate is expired
+    pub not_after: i64,
+    /// DER encoded SubjectPublicKeyInfo
+    pub spki: &'a [u8],
+    /// Subject, as in `CN=example.org, O=Example`. Only the common attributes are kept
+    pub subject: String,
+}
+
+impl<'a> Certificate<'a> {
+    pub fn parse(der: &'a [u8]) -> Option<Self> {
+        let (cert, _) = read(der, SEQUENCE)?;
+        let (tbs, _) = read(cert.value, SEQUENCE)?;
+        let mut rest = tbs.value;
+
+        // The version is optional, the serial number isn't
+        if let Some((_, after)) = read(rest, CONTEXT_0) {
+            rest = after;
+        }
+        let (_, rest) = read(rest, INTEGER)?;
+        let (_signature, rest) = read(rest, SEQUENCE)?;
+        let (_issuer, rest) = read(rest, SEQUENCE)?;
+        let (validity, rest) = read(rest, SEQUENCE)?;
+        let (subject, rest) = read(rest, SEQUENCE)?;
+        let (spki, _) = read(rest, SEQUENCE)?;
+
+        let (not_before, validity) = read_time(validity.value)?;
+        let (not_after, _) = read_time(validity)?;
+        Some(Self {
+            not_before,
+            not_after,
+            spki: spki.raw,
+            subject: read_name(subject.value)?,
+        })
+    }
+}
+
+struct Tlv<'a> {
+    /// The whole element, with its tag and length
+    raw: &'a [u8],
+    value: &'a [u8],
+}
+
+/// Reads the element at the start of `data` if it has the tag `tag`, returning it with the
+/// bytes following it
+fn read(data: &[u8], tag: u8) -> Option<(Tlv<'_>, &[u8])> {
+    if *data.first()? != tag {
+        return None;
+    }
+    let first = *data.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + n)?
+            .iter()
+            .fold(0, |len, b| (len << 8) | *b as usize);
+        (len, 2 + n)
+    };
+    let end = header.checked_add(len)?;
+    let raw = data.get(..end)?;
+    Some((
+        Tlv {
+            raw,
+            value: &raw[header..],
+        },
+        &data[end..],
+    ))
+}
+
+/// Formats the attributes of a Name, a sequence of sets of (OID, string) pairs
+fn read_name(mut data: &[u8]) -> Option<String> {
+    let mut parts = vec![];
+    while !data.is_empty() {
+        let (rdn, rest) = read(data, SET)?;
+        data = rest;
+        let mut attributes = rdn.value;
+        while !attributes.is_empty() {
+            let (attribute, rest) = read(attributes, SEQUENCE)?;
+            attributes = rest;
+            let (oid, value) = read(attribute.value, OID)?;
+            let (value, _) = read(value, *value.first()?)?;
+            let name = ATTRIBUTES.iter().find(|(known, _)| *known == oid.value);
+            // The strings using other encodings, as BMPString, are skipped
+            if let (Some((_, name)), Ok(value)) = (name, std::str::from_utf8(value.value)) {
+                parts.push(format!("{}={}", name, value));
+            }
+        }
+    }
+    Some(parts.join(", "))
+}
+
+fn read_time(data: &[u8]) -> Option<(i64, &[u8])> {
+    let ((time, rest), year_digits) = match *data.first()? {
+        UTC_TIME => (read(data, UTC_TIME)?, 2),
+        GENERALIZED_TIME => (read(data, GENERALIZED_TIME)?, 4),
+        _ => return None,
+    };
+    let s = std::str::from_utf8(time.value).ok()?;
+    let s = s.strip_suffix('Z')?;
+    if s.len() != year_digits + 10 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| s[range].parse::<i64>().ok();
+
+    let mut year = num(0..year_digits)?;
+    if year_digits == 2 {
+        // As defined by RFC 5280
+        year += if year >= 50 { 1900 } else { 2000 };
+    }
+    let d = year_digits;
+    let (month, day) = (num(d..d + 2)?, num(d + 2..d + 4)?);
+    let (hour, min, sec) = (num(d + 4..d + 6)?, num(d + 6..d + 8)?, num(d + 8..d + 10)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    Some((days * 86400 + hour * 3600 + min * 60 + sec, rest))
+}
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(
+            read_time(b"\x17\x0d491231235959Z").map(|(t, _)| t),
+            Some(2524607999)
+        );
+        assert_eq!(
+            read_time(b"\x18\x0f19700102000000Z").map(|(t, _)| t),
+            Some(86400)
+        );
+        assert_eq!(read_time(b"\x17\x0d4912312359590"), None);
+    }
+
+    #[test]
+    fn parse_certificate() {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "localhost");
+        params
+            .distinguished_name
+            .push(rcgen::DnType::OrganizationName, "Geopard");
+        params.not_before = rcgen::date_time_ymd(2020, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2060, 1, 1);
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key_pair).unwrap();
+
+        let parsed = Certificate::parse(cert.der()).unwrap();
+        assert_eq!(parsed.not_before, 1577836800);
+        assert_eq!(parsed.not_after, 2840140800);
+        assert!(parsed.spki.ends_with(key_pair.public_key_raw()));
+        assert_eq!(parsed.subject, "CN=localhost, O=Geopard");
+        assert_eq!(Certificate::parse(&cert.der()[..100]), None);
+    }
+}
@@ -0,0 +1,47 @@
+use futures::prelude::*;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::client::send_request;
+use crate::{Client, Error};
+
+const DEFAULT_PORT: u16 = 79;
+
+/// Query to send to the server. Both `finger://host/user` and `finger://user@host` are accepted
+fn query(url: &Url) -> String {
+    let user = match url.username() {
+        "" => url.path().trim_start_matches('/'),
+        user => user,
+    };
+    percent_decode_str(user).decode_utf8_lossy().into_owned()
+}
+
+impl Client {
+    /// Fetches a finger url, returning the raw text sent by the server
+    pub async fn fetch_finger(&self, url_str: &str) -> Result<impl AsyncRead + Unpin, Error> {
+        let url = Url::parse(url_str)?;
+        if url.scheme() != "finger" {
+            return Err(Error::SchemeNotSupported);
+        }
+        let connection = self.connect_plain(&url, DEFAULT_PORT).await?;
+        let request = query(&url) + "\r\n";
+        send_request(connection, request.into_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_query() {
+        let url = Url::parse("finger://example.org/alice").unwrap();
+        assert_eq!(query(&url), "alice");
+
+        let url = Url::parse("finger://bob@example.org").unwrap();
+        assert_eq!(query(&url), "bob");
+
+        let url = Url::parse("finger://example.org").unwrap();
+        assert_eq!(query(&url), "");
+    }
+}
@@ -0,0 +1,184 @@
+use futures::prelude::*;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use url::Url;
+
+use crate::{Client, Error, Event, LineParser, Tag};
+
+const DEFAULT_PORT: u16 = 70;
+
+// Characters of a selector that can't appear as they are in the path of a gopher url
+const SELECTOR: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`');
+
+/// Item type of a gopher url (`gopher://host/<type><selector>`), defaulting to a menu
+pub fn item_type(url: &Url) -> char {
+    url.path().chars().nth(1).unwrap_or('1')
+}
+
+/// Selector to send to the server. The search query of type 7 items can be given either
+/// as the url query or inline, after an encoded tab
+pub fn selector(url: &Url) -> String {
+    let raw = url
+        .path()
+        .get(1..)
+        .map(|path| {
+            let mut chars = path.chars();
+            chars.next();
+            chars.as_str()
+        })
+        .unwrap_or("");
+
+    let mut selector = percent_decode_str(raw).decode_utf8_lossy().into_owned();
+    if let Some(query) = url.query() {
+        selector.push('\t');
+        selector.push_str(&percent_decode_str(query).decode_utf8_lossy());
+    }
+    selector
+}
+
+fn menu_url(item_type: char, selector: &str, host: &str, port: u16) -> String {
+    let selector = utf8_percent_encode(selector, SELECTOR);
+    if port == DEFAULT_PORT {
+        format!("gopher://{host}/{item_type}{selector}")
+    } else {
+        format!("gopher://{host}:{port}/{item_type}{selector}")
+    }
+}
+
+impl Client {
+    /// Fetches a gopher url, returning the raw body sent by the server
+    pub async fn fetch_gopher(&self, url_str: &str) -> Result<impl AsyncRead + Unpin, Error> {
+        let url = Url::parse(url_str)?;
+        if url.scheme() != "gopher" {
+            return Err(Error::SchemeNotSupported);
+        }
+        let connection = self.connect_plain(&url, DEFAULT_PORT).await?;
+        let request = selector(&url) + "\r\n";
+        crate::client::send_request(connection, request.into_bytes()).await
+    }
+}
+
+/// Turns the lines of a gopher menu into the same events produced by [`crate::Parser`].
+/// Info lines become paragraphs, every other item becomes a link
+#[derive(Debug, Clone, Default)]
+pub struct MenuParser {
+    done: bool,
+}
+
+impl MenuParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LineParser for MenuParser {
+    fn parse_line<'a>(&mut self, line: &'a str, res: &mut Vec<Event<'a>>) {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if self.done {
+            return;
+        }
+        if line == "." {
+            self.done = true;
+            return;
+        }
+
+        let mut chars = line.chars();
+        let Some(item_type) = chars.next() else {
+            res.push(Event::BlankLine);
+            return;
+        };
+        let mut fields = chars.as_str().split('\t');
+        let display = fields.next().unwrap_or("");
+        let selector = fields.next().unwrap_or("");
+        let host = fields.next().unwrap_or("");
+        let port = fields
+            .next()
+            .and_then(|port| port.trim().parse().ok())
+            .unwrap_or(DEFAULT_PORT);
+
+        let href = match item_type {
+            'i' | '3' => {
+                if display.trim().is_empty() {
+                    res.push(Event::BlankLine);
+                } else {
+                    res.push(Event::Start(Tag::Paragraph));
+                    res.push(Event::Text(display));
+                    res.push(Event::End);
+                }
+                return;
+            }
+            'h' if selector.starts_with("URL:") => selector["URL:".len()..].to_owned(),
+            _ => menu_url(item_type, selector, host, port),
+        };
+        let label = (!display.is_empty()).then(|| display.to_owned());
+        res.push(Event::Start(Tag::Link(href, label)));
+        res.push(Event::End);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(menu: &str) -> Vec<String> {
+        let mut parser = MenuParser::new();
+        let mut events = vec![];
+        for line in menu.split_inclusive('\n') {
+            parser.parse_line(line, &mut events);
+        }
+        events.iter().map(|ev| format!("{:?}", ev)).collect()
+    }
+
+    #[test]
+    fn url_parts() {
+        let url = Url::parse("gopher://example.org/0/docs/read%20me.txt").unwrap();
+        assert_eq!(item_type(&url), '0');
+        assert_eq!(selector(&url), "/docs/read me.txt");
+
+        let url = Url::parse("gopher://example.org").unwrap();
+        assert_eq!(item_type(&url), '1');
+        assert_eq!(selector(&url), "");
+
+        let url = Url::parse("gopher://example.org/7/search?rust%20gtk").unwrap();
+        assert_eq!(item_type(&url), '7');
+        assert_eq!(selector(&url), "/search\trust gtk");
+    }
+
+    #[test]
+    fn menu_items() {
+        let events = parse(
+            "iWelcome\tfake\t(NULL)\t0\r\n\
+            i\tfake\t(NULL)\t0\r\n\
+            1Phlog\t/phlog\texample.org\t70\r\n\
+            0About me\t/about.txt\texample.org\t7070\r\n\
+            7Search\t/search\texample.org\t70\r\n\
+            hWebsite\tURL:https://example.org\texample.org\t70\r\n\
+            .\r\n\
+            iIgnored\tfake\t(NULL)\t0\r\n",
+        );
+        assert_eq!(
+            events,
+            [
+                "Start(Paragraph)",
+                "Text(\"Welcome\")",
+                "End",
+                "BlankLine",
+                "Start(Link(\"gopher://example.org/1/phlog\", Some(\"Phlog\")))",
+                "End",
+                "Start(Link(\"gopher://example.org:7070/0/about.txt\", Some(\"About me\")))",
+                "End",
+                "Start(Link(\"gopher://example.org/7/search\", Some(\"Search\")))",
+                "End",
+                "Start(Link(\"https://example.org\", Some(\"Website\")))",
+                "End",
+            ]
+        );
+    }
+}
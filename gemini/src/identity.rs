@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use url::Url;
+
+const SCOPES_FILE: &str = "scopes";
+const PEM_EXT: &str = "pem";
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdentityError {
+    #[error("Io error: {0:?}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to generate the certificate: {0}")]
+    Generate(#[from] rcgen::Error),
+    #[error("Invalid identity name {0:?}")]
+    InvalidName(String),
+    #[error("An identity named {0:?} already exists")]
+    AlreadyExists(String),
+    #[error("No identity named {0:?}")]
+    NotFound(String),
+}
+
+/// Chooses the client certificate to present when connecting to `url`
+pub trait IdentityProvider {
+    fn identity_for(&mut self, url: &Url) -> Option<gio::TlsCertificate>;
+}
+
+impl<F: FnMut(&Url) -> Option<gio::TlsCertificate>> IdentityProvider for F {
+    fn identity_for(&mut self, url: &Url) -> Option<gio::TlsCertificate> {
+        self(url)
+    }
+}
+
+/// A named client certificate, stored as the PEM encoded certificate followed by its private key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    name: String,
+    pem: String,
+}
+
+impl Identity {
+    /// Generates a new self-signed certificate, using `name` as the common name
+    pub fn generate(name: &str) -> Result<Self, IdentityError> {
+        validate_name(name)?;
+
+        let mut params = rcgen::CertificateParams::default();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let key_pair = rcgen::KeyPair::generate()?;
+        let cert = params.self_signed(&key_pair)?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            pem: cert.pem() + &key_pair.serialize_pem(),
+        })
+    }
+    pub fn from_pem(name: &str, pem: String) -> Self {
+        Self {
+            name: name.to_owned(),
+            pem,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn pem(&self) -> &str {
+        &self.pem
+    }
+    pub fn certificate(&self) -> Result<gio::TlsCertificate, glib::Error> {
+        gio::TlsCertificate::from_pem(&self.pem)
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), IdentityError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !name.starts_with('.');
+    if valid {
+        Ok(())
+    } else {
+        Err(IdentityError::InvalidName(name.to_owned()))
+    }
+}
+
+/// Scope covering every page of the host (and port) of `url`
+pub fn host_scope(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}://{}:{}/", url.scheme(), host, port),
+        None => format!("{}://{}/", url.scheme(), host),
+    })
+}
+
+/// Scope covering `url` and every page under it
+pub fn page_scope(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    url.to_string()
+}
+
+/// Identities and the url prefixes they are used for.
+///
+/// When opened from a directory, each identity is saved as `<name>.pem`, while the scopes are
+/// saved in the `scopes` file, one `<url prefix> <name>` pair per line.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityStore {
+    dir: Option<PathBuf>,
+    identities: BTreeMap<String, Identity>,
+    scopes: BTreeMap<String, String>,
+}
+
+impl IdentityStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn open(dir: &Path) -> Result<Self, IdentityError> {
+        let mut identities = BTreeMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(PEM_EXT) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                let pem = fs::read_to_string(&path)?;
+                identities.insert(name.to_owned(), Identity::from_pem(name, pem));
+            }
+        }
+
+        let mut scopes = BTreeMap::new();
+        match fs::read_to_string(dir.join(SCOPES_FILE)) {
+            Ok(lines) => {
+                for line in lines.lines() {
+                    let mut parts = line.split(' ');
+                    if let (Some(scope), Some(name)) = (parts.next(), parts.next()) {
+                        if identities.contains_key(name) {
+                            scopes.insert(scope.to_owned(), name.to_owned());
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self {
+            dir: Some(dir.to_owned()),
+            identities,
+            scopes,
+        })
+    }
+    pub fn identities(&self) -> impl Iterator<Item = &Identity> {
+        self.identities.values()
+    }
+    pub fn get(&self, name: &str) -> Option<&Identity> {
+        self.identities.get(name)
+    }
+    pub fn create(&mut self, name: &str) -> Result<&Identity, IdentityError> {
+        if self.identities.contains_key(name) {
+            return Err(IdentityError::AlreadyExists(name.to_owned()));
+        }
+        let identity = Identity::generate(name)?;
+        if let Some(dir) = &self.dir {
+            write_private(&dir.join(name).with_extension(PEM_EXT), identity.pem())?;
+        }
+        Ok(self.identities.entry(name.to_owned()).or_insert(identity))
+    }
+    pub fn remove(&mut self, name: &str) -> Result<bool, IdentityError> {
+        if self.identities.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.scopes.retain(|_, scope_name| scope_name != name);
+        if let Some(dir) = &self.dir {
+            fs::remove_file(dir.join(name).with_extension(PEM_EXT))?;
+        }
+        self.save_scopes()?;
+        Ok(true)
+    }
+    pub fn scopes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.scopes.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+    pub fn add_scope(&mut self, scope: &str, name: &str) -> Result<(), IdentityError> {
+        if !self.identities.contains_key(name) {
+            return Err(IdentityError::NotFound(name.to_owned()));
+        }
+        self.scopes.insert(scope.to_owned(), name.to_owned());
+        self.save_scopes()?;
+        Ok(())
+    }
+    pub fn remove_scope(&mut self, scope: &str) -> Result<bool, IdentityError> {
+        let removed = self.scopes.remove(scope).is_some();
+        self.save_scopes()?;
+        Ok(removed)
+    }
+    /// Finds the identity with the most specific scope matching `url`
+    pub fn find(&self, url: &Url) -> Option<&Identity> {
+        self.scopes
+            .iter()
+            .filter(|(scope, _)| scope_matches(scope, url))
+            .max_by_key(|(scope, _)| scope.len())
+            .and_then(|(_, name)| self.identities.get(name))
+    }
+    fn save_scopes(&self) -> std::io::Result<()> {
+        if let Some(dir) = &self.dir {
+            let lines: String = self
+                .scopes
+                .iter()
+                .map(|(scope, name)| format!("{scope} {name}\n"))
+                .collect();
+            fs::write(dir.join(SCOPES_FILE), lines)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `url` is covered by `scope`: same scheme, host and port, and a path equal to the
+/// scope path or under it. `gemini://h/wiki/edit` covers `gemini://h/wiki/edit/page`, but
+/// not `gemini://h/wiki/editor`
+fn scope_matches(scope: &str, url: &Url) -> bool {
+    let Ok(scope) = Url::parse(scope) else {
+        return false;
+    };
+    let port = |url: &Url| url.port_or_known_default().unwrap_or(1965);
+    if scope.scheme() != url.scheme() || scope.host() != url.host() || port(&scope) != port(url) {
+        return false;
+    }
+    let (scope, path) = (scope.path(), url.path());
+    path == scope
+        || (scope.ends_with('/') && path.starts_with(scope))
+        || path
+            .strip_prefix(scope)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Writes `contents` to a file only readable by the user, as it contains a private key
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_contains_key() -> Result<(), IdentityError> {
+        let identity = Identity::generate("test")?;
+        assert!(identity.pem().contains("BEGIN CERTIFICATE"));
+        assert!(identity.pem().contains("BEGIN PRIVATE KEY"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert!(Identity::generate("").is_err());
+        assert!(Identity::generate("../escape").is_err());
+        assert!(Identity::generate("with space").is_err());
+    }
+
+    #[test]
+    fn most_specific_scope() -> Result<(), IdentityError> {
+        let mut store = IdentityStore::new();
+        store.create("host")?;
+        store.create("page")?;
+        let url = Url::parse("gemini://example.org/wiki/edit?x").unwrap();
+        store.add_scope(&host_scope(&url).unwrap(), "host")?;
+        store.add_scope("gemini://example.org/wiki/", "page")?;
+
+        assert_eq!(store.find(&url).map(|i| i.name()), Some("page"));
+        let other = Url::parse("gemini://example.org/other").unwrap();
+        assert_eq!(store.find(&other).map(|i| i.name()), Some("host"));
+        let other_host = Url::parse("gemini://example.com/").unwrap();
+        assert!(store.find(&other_host).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn scope_boundaries() -> Result<(), IdentityError> {
+        let mut store = IdentityStore::new();
+        store.create("edit")?;
+        store.add_scope("gemini://example.org/wiki/edit", "edit")?;
+        let find = |url: &str| store.find(&Url::parse(url).unwrap()).map(|i| i.name());
+
+        assert_eq!(find("gemini://example.org/wiki/edit"), Some("edit"));
+        assert_eq!(find("gemini://example.org/wiki/edit/page?x"), Some("edit"));
+        assert_eq!(find("gemini://example.org:1965/wiki/edit"), Some("edit"));
+        // Neither other paths sharing the prefix, nor other hosts, ports or schemes
+        assert_eq!(find("gemini://example.org/wiki/editor"), None);
+        assert_eq!(find("gemini://example.org:1966/wiki/edit"), None);
+        assert_eq!(find("titan://example.org/wiki/edit"), None);
+
+        store.add_scope("gemini://example.org", "edit")?;
+        assert_eq!(find("gemini://example.org/other"), Some("edit"));
+        assert_eq!(find("gemini://example.org.evil.example/"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn persisted_store() -> Result<(), IdentityError> {
+        let dir = std::env::temp_dir().join(format!("gemini-identities-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+
+        let mut store = IdentityStore::open(&dir)?;
+        store.create("persisted")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = fs::metadata(dir.join("persisted.pem"))?;
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        store.add_scope("gemini://example.org/", "persisted")?;
+
+        let store = IdentityStore::open(&dir)?;
+        let url = Url::parse("gemini://example.org/page").unwrap();
+        assert_eq!(store.find(&url).map(|i| i.name()), Some("persisted"));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
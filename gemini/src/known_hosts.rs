@@ -1,9 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{hash_map, HashMap};
 use std::fs;
-use std::io::{Read, Seek, Write};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use gio::prelude::*;
 
+use crate::der;
+
+pub mod interop;
+pub mod overrides;
+
 #[derive(Debug, Clone, Copy, thiserror::Error, PartialEq, Eq)]
 pub enum CertificateError {
     #[error("Certificate is expired")]
@@ -14,123 +21,958 @@ pub enum CertificateError {
     NotActivated,
     #[error("Certificate is not valid for this host")]
     BadIdentity,
+    #[error("Certificate is not signed by a trusted authority")]
+    UnknownAuthority,
     #[error("Generic certificate error")]
     GenericError,
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum KnownHostsError {
+    #[error("Io error: {0:?}")]
+    Io(#[from] io::Error),
+}
+
+/// Certificate trusted for a host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownHost {
+    /// Sha256 of the DER encoded certificate, `-` for the hosts imported with only the
+    /// public key fingerprint
+    pub fingerprint: String,
+    /// Sha256 of the DER encoded public key info. Unknown for the entries of the old format
+    pub spki_fingerprint: Option<String>,
+    /// Unix time at which the certificate was first seen
+    pub first_seen: i64,
+    /// Unix time after which the certificate is expired
+    pub expires: Option<i64>,
+    /// Only this exact certificate is accepted, even when the server renews it
+    pub pinned: bool,
+}
+
+impl KnownHost {
+    /// Entry for the DER encoded certificate `der`, seen at `now`
+    pub fn from_der(der: &[u8], now: i64) -> Self {
+        let info = CertificateInfo::from_der(der);
+        Self {
+            fingerprint: info.fingerprint,
+            spki_fingerprint: info.spki_fingerprint,
+            first_seen: now,
+            expires: info.not_after,
+            pinned: false,
+        }
+    }
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires < now)
+    }
+}
+
+/// Details of a certificate presented by a server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateInfo {
+    /// Sha256 of the DER encoded certificate
+    pub fingerprint: String,
+    /// Sha256 of the DER encoded public key info
+    pub spki_fingerprint: Option<String>,
+    /// Subject, as in `CN=example.org, O=Example`
+    pub subject: Option<String>,
+    /// Unix time from which the certificate is valid
+    pub not_before: Option<i64>,
+    /// Unix time after which the certificate is expired
+    pub not_after: Option<i64>,
+}
+
+impl CertificateInfo {
+    /// Details of the DER encoded certificate `der`. If it can't be parsed, only its
+    /// fingerprint is known
+    pub fn from_der(der: &[u8]) -> Self {
+        let cert = der::Certificate::parse(der);
+        Self {
+            fingerprint: fingerprint(der),
+            spki_fingerprint: cert.as_ref().map(|cert| fingerprint(cert.spki)),
+            not_before: cert.as_ref().map(|cert| cert.not_before),
+            not_after: cert.as_ref().map(|cert| cert.not_after),
+            subject: cert.map(|cert| cert.subject),
+        }
+    }
+    pub fn from_certificate(cert: &gio::TlsCertificate) -> Option<Self> {
+        cert.certificate().map(|der| Self::from_der(&der))
+    }
+}
+
+/// Hex encoded sha256 of `data`
+pub fn fingerprint(data: &[u8]) -> String {
+    let mut ck = glib::Checksum::new(glib::ChecksumType::Sha256).unwrap();
+    ck.update(data);
+    ck.string().unwrap()
+}
+
 pub trait KnownHostsRepo: std::fmt::Debug {
-    fn get(&self, host: &str) -> Option<&str>;
-    fn insert(&mut self, host: &str, sha: &str) -> bool;
-    fn remove(&mut self, host: &str) -> bool;
-    fn values(&self) -> HashMap<String, String>;
+    fn get(&self, host: &str) -> Option<&KnownHost>;
+    fn insert(&mut self, host: &str, known_host: KnownHost) -> Result<bool, KnownHostsError>;
+    fn remove(&mut self, host: &str) -> Result<bool, KnownHostsError>;
+    fn values(&self) -> HashMap<String, KnownHost>;
+    /// Loads the changes made by other users of the repo, if it's shared
+    fn refresh(&mut self) -> Result<(), KnownHostsError> {
+        Ok(())
+    }
+    /// Adds the hosts that aren't known yet, the first entry of a host winning, and returns
+    /// the number of hosts added
+    fn insert_new(
+        &mut self,
+        known_hosts: Vec<(String, KnownHost)>,
+    ) -> Result<usize, KnownHostsError> {
+        let mut added = 0;
+        for (host, known_host) in known_hosts {
+            if self.get(&host).is_none() && self.insert(&host, known_host)? {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+}
+
+/// Key of the known hosts repos, as in `example.org:1965` or `[::1]:1965`. The brackets of an
+/// IPv6 `host` are optional
+pub fn host_key(host: &str, port: u16) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Key of an entry stored before the port was part of the key, using the default port
+fn migrate_key(key: &str) -> Option<String> {
+    let has_port = match key.rsplit_once(':') {
+        Some((host, port)) => {
+            port.parse::<u16>().is_ok() && (!host.contains(':') || host.ends_with(']'))
+        }
+        None => false,
+    };
+    (!has_port).then(|| host_key(key, 1965))
 }
 
 pub fn validate(
-    repo: &mut impl KnownHostsRepo,
+    repo: &mut (impl KnownHostsRepo + ?Sized),
+    host: &str,
+    port: u16,
+    cert: &gio::TlsCertificate,
+) -> Result<(), CertificateError> {
+    let der = cert.certificate().ok_or(CertificateError::GenericError)?;
+    validate_der(repo, host, port, &der)
+}
+
+/// How the certificates of a host are validated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ValidationMode {
+    /// Only trust on first use, see [`validate_der`], even for the certificates signed by a
+    /// trusted authority
+    Tofu,
+    /// Accept the certificates signed by a trusted authority, even when they replace the known
+    /// one, and fall back to trust on first use for the others
+    #[default]
+    CaOrTofu,
+    /// Only accept the certificates signed by a trusted authority
+    Ca,
+}
+
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
+#[error("Unknown validation mode {0:?}, expected tofu, ca-or-tofu or ca")]
+pub struct ParseValidationModeError(String);
+
+impl std::str::FromStr for ValidationMode {
+    type Err = ParseValidationModeError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tofu" => Ok(Self::Tofu),
+            "ca-or-tofu" => Ok(Self::CaOrTofu),
+            "ca" => Ok(Self::Ca),
+            _ => Err(ParseValidationModeError(s.to_owned())),
+        }
+    }
+}
+
+/// Validates `cert` according to `mode`. `errors` are the errors found by checking its chain
+/// against the system trust store, as reported by gio
+pub fn validate_with_mode(
+    repo: &mut (impl KnownHostsRepo + ?Sized),
     host: &str,
+    port: u16,
     cert: &gio::TlsCertificate,
+    errors: gio::TlsCertificateFlags,
+    mode: ValidationMode,
 ) -> Result<(), CertificateError> {
-    let cert_sha = {
-        let mut ck = glib::Checksum::new(glib::ChecksumType::Sha256).unwrap();
-        ck.update(&cert.certificate().unwrap());
-        ck.string().unwrap()
+    let der = cert.certificate().ok_or(CertificateError::GenericError)?;
+    if let Err(e) = repo.refresh() {
+        log::warn!("Failed to reload the known hosts: {}", e);
+    }
+    validate_chain_at(
+        repo,
+        &host_key(host, port),
+        &der,
+        chain_result(errors),
+        mode,
+        unix_now(),
+    )
+}
+
+/// The first error among `errors`, or `Ok` if the chain is trusted
+fn chain_result(errors: gio::TlsCertificateFlags) -> Result<(), CertificateError> {
+    use gio::TlsCertificateFlags as Flags;
+    if errors.is_empty() {
+        Ok(())
+    } else if errors.contains(Flags::BAD_IDENTITY) {
+        Err(CertificateError::BadIdentity)
+    } else if errors.contains(Flags::EXPIRED) {
+        Err(CertificateError::Expired)
+    } else if errors.contains(Flags::NOT_ACTIVATED) {
+        Err(CertificateError::NotActivated)
+    } else if errors.contains(Flags::REVOKED) {
+        Err(CertificateError::Revoked)
+    } else if errors.contains(Flags::UNKNOWN_CA) {
+        Err(CertificateError::UnknownAuthority)
+    } else {
+        Err(CertificateError::GenericError)
+    }
+}
+
+/// Trusts the first certificate seen for `host:port`, then only accepts the same certificate.
+///
+/// A new certificate is accepted in place of the known one if the known one has expired, or
+/// if it has the same public key, as when a capsule renews its certificate, unless the known
+/// one is pinned.
+pub fn validate_der(
+    repo: &mut (impl KnownHostsRepo + ?Sized),
+    host: &str,
+    port: u16,
+    der: &[u8],
+) -> Result<(), CertificateError> {
+    if let Err(e) = repo.refresh() {
+        log::warn!("Failed to reload the known hosts: {}", e);
+    }
+    validate_at(repo, &host_key(host, port), der, unix_now())
+}
+
+/// Saves `known_host`, keeping it only for this session if the repo fails to store it
+fn trust(repo: &mut (impl KnownHostsRepo + ?Sized), host: &str, known_host: KnownHost) {
+    if let Err(e) = repo.insert(host, known_host) {
+        log::error!("Failed to save the certificate of {}: {}", host, e);
+    }
+}
+
+fn validate_at(
+    repo: &mut (impl KnownHostsRepo + ?Sized),
+    host: &str,
+    der: &[u8],
+    now: i64,
+) -> Result<(), CertificateError> {
+    let seen = KnownHost::from_der(der, now);
+    // A certificate outside of its validity period is refused without being remembered
+    let validity = match der::Certificate::parse(der) {
+        Some(cert) if cert.not_after < now => Err(CertificateError::Expired),
+        Some(cert) if cert.not_before > now => Err(CertificateError::NotActivated),
+        _ => Ok(()),
     };
-    {
-        if let Some(known_host_sha) = repo.get(host) {
-            if known_host_sha != cert_sha {
-                return Err(CertificateError::BadIdentity);
-            }
-            if let Some(cert_end_date) = cert.not_valid_after() {
-                if cert_end_date < glib::DateTime::now_utc().unwrap() {
-                    return Err(CertificateError::Expired);
-                }
-            }
-            if let Some(cert_start_date) = cert.not_valid_before() {
-                if cert_start_date > glib::DateTime::now_utc().unwrap() {
-                    return Err(CertificateError::NotActivated);
-                }
+    match repo.get(host) {
+        Some(known) if known.fingerprint == seen.fingerprint => {
+            // Complete the entries migrated from the old format
+            if known.spki_fingerprint.is_none() && seen.spki_fingerprint.is_some() {
+                let known_host = KnownHost {
+                    first_seen: known.first_seen,
+                    pinned: known.pinned,
+                    ..seen
+                };
+                trust(repo, host, known_host);
             }
-
-            return Ok(());
+        }
+        Some(known)
+            if !known.pinned
+                && (known.is_expired(now)
+                    || (known.spki_fingerprint.is_some()
+                        && known.spki_fingerprint == seen.spki_fingerprint)) =>
+        {
+            validity?;
+            log::info!("Accepting the renewed certificate of {}", host);
+            trust(repo, host, seen);
+        }
+        Some(_) => return Err(CertificateError::BadIdentity),
+        None => {
+            validity?;
+            trust(repo, host, seen);
         }
     }
-    repo.insert(host, &cert_sha);
-    validate(repo, host, cert)
+    validity
+}
+
+/// Validates the certificate `der` of `host` according to `mode`, `chain` being the result of
+/// checking it against the system trust store.
+///
+/// The certificates signed by a trusted authority are remembered in place of the known one,
+/// so that trust on first use still detects a later switch to an untrusted certificate. A
+/// pinned certificate is never replaced.
+fn validate_chain_at(
+    repo: &mut (impl KnownHostsRepo + ?Sized),
+    host: &str,
+    der: &[u8],
+    chain: Result<(), CertificateError>,
+    mode: ValidationMode,
+    now: i64,
+) -> Result<(), CertificateError> {
+    if mode == ValidationMode::Tofu {
+        return validate_at(repo, host, der, now);
+    }
+    let seen = KnownHost::from_der(der, now);
+    // Whether the known certificate that `der` would replace is pinned
+    let replaced = repo
+        .get(host)
+        .filter(|known| known.fingerprint != seen.fingerprint)
+        .map(|known| known.pinned);
+    match chain {
+        Ok(()) => match replaced {
+            Some(true) => Err(CertificateError::BadIdentity),
+            Some(false) => {
+                log::info!(
+                    "Accepting the new certificate of {}, signed by a trusted authority",
+                    host
+                );
+                trust(repo, host, seen);
+                Ok(())
+            }
+            None => validate_at(repo, host, der, now),
+        },
+        Err(e) if mode == ValidationMode::Ca => Err(e),
+        Err(_) => validate_at(repo, host, der, now),
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
 }
 
 #[derive(Debug, Clone, Default)]
-pub struct KnownHostsMap(HashMap<String, String>);
+pub struct KnownHostsMap(HashMap<String, KnownHost>);
 impl KnownHostsMap {
     pub fn new() -> Self {
         Self::default()
     }
 }
 impl KnownHostsRepo for KnownHostsMap {
-    fn get(&self, host: &str) -> Option<&str> {
-        self.0.get(host).map(|s| s.as_str())
+    fn get(&self, host: &str) -> Option<&KnownHost> {
+        self.0.get(host)
     }
 
-    fn insert(&mut self, host: &str, sha: &str) -> bool {
-        self.0.insert(host.to_string(), sha.to_string()).is_none()
+    fn insert(&mut self, host: &str, known_host: KnownHost) -> Result<bool, KnownHostsError> {
+        Ok(self.0.insert(host.to_string(), known_host).is_none())
     }
 
-    fn remove(&mut self, host: &str) -> bool {
-        self.0.remove(host).is_some()
+    fn remove(&mut self, host: &str) -> Result<bool, KnownHostsError> {
+        Ok(self.0.remove(host).is_some())
     }
-    fn values(&self) -> HashMap<String, String> {
+    fn values(&self) -> HashMap<String, KnownHost> {
         self.0.clone()
     }
 }
 
+/// Known hosts stored in a file, one host per line:
+/// `host fingerprint first_seen expires spki_fingerprint`, with `-` for the unknown values,
+/// followed by `pinned` for the pinned hosts.
+///
+/// The host is stored with its port, as returned by [`host_key`]. The lines of the old formats,
+/// `host fingerprint` or without a port, are migrated when the file is loaded, using the
+/// default port. When a host appears more than once, the last line wins.
+///
+/// The file can be shared by several processes: each change is made while holding a lock on
+/// a `.lock` file next to it, on top of the entries currently stored, and written to a
+/// temporary file renamed over the old one.
 #[derive(Debug)]
 pub struct KnownHostsFile {
-    file: fs::File,
+    path: PathBuf,
     known_hosts: KnownHostsMap,
+    /// Modification time and size of the file when it was last read or written
+    stamp: Option<(SystemTime, u64)>,
+    /// Changes that couldn't be saved yet, applied again on top of the stored entries. `None`
+    /// for a removed host
+    pending: HashMap<String, Option<KnownHost>>,
 }
 
 impl KnownHostsFile {
-    pub fn new(file: fs::File) -> Self {
-        let mut known_hosts = KnownHostsMap::new();
-        let mut bf = std::io::BufReader::new(file);
-        let mut lines = String::new();
-        bf.read_to_string(&mut lines).unwrap();
-        lines.split('\n').for_each(|line| {
-            let mut parts = line.split(' ');
-            if let (Some(host), Some(sha)) = (parts.next(), parts.next()) {
-                known_hosts.insert(host, sha);
+    /// Loads the known hosts stored at `path`. A missing file is created at the first change
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, KnownHostsError> {
+        let mut this = Self {
+            path: path.into(),
+            known_hosts: KnownHostsMap::new(),
+            stamp: None,
+            pending: HashMap::new(),
+        };
+        this.reload()?;
+        Ok(this)
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    fn sibling(&self, ext: &str) -> PathBuf {
+        sibling(&self.path, ext)
+    }
+    /// Exclusive lock on the file, released when the returned file is dropped
+    fn lock(&self) -> Result<fs::File, KnownHostsError> {
+        Ok(lock_file(&self.sibling(".lock"))?)
+    }
+    fn stamp_on_disk(&self) -> Result<Option<(SystemTime, u64)>, KnownHostsError> {
+        stamp(&self.path)
+    }
+    /// Replaces the entries with the stored ones and the pending changes, returning true if
+    /// they must be saved again, to migrate them or to save the pending changes. Must be called
+    /// holding the lock
+    fn load(&mut self) -> Result<bool, KnownHostsError> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let (mut known_hosts, migrated) = parse(&text);
+        for (host, change) in &self.pending {
+            match change {
+                Some(known_host) => known_hosts.0.insert(host.clone(), known_host.clone()),
+                None => known_hosts.0.remove(host),
+            };
+        }
+        self.known_hosts = known_hosts;
+        self.stamp = self.stamp_on_disk()?;
+        Ok(migrated || !self.pending.is_empty())
+    }
+    /// Atomically replaces the stored entries. Must be called holding the lock
+    fn save(&mut self) -> Result<(), KnownHostsError> {
+        let tmp_path = self.sibling(".tmp");
+        let mut tmp = fs::File::create(&tmp_path)?;
+        let mut hosts: Vec<_> = self.known_hosts.0.iter().collect();
+        hosts.sort_by_key(|(host, _)| *host);
+        for (host, known_host) in hosts {
+            tmp.write_all(format_line(host, known_host).as_bytes())?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.stamp = self.stamp_on_disk()?;
+        self.pending.clear();
+        Ok(())
+    }
+    fn reload(&mut self) -> Result<(), KnownHostsError> {
+        let _lock = self.lock()?;
+        if self.load()? {
+            self.save()?;
+        }
+        Ok(())
+    }
+    /// Applies `f` to the stored entries, including the ones added by other processes. If the
+    /// entries can't be saved, the change is only kept in memory, and saved again with the
+    /// next change
+    fn update<T>(&mut self, f: impl FnOnce(&mut KnownHostsMap) -> T) -> Result<T, KnownHostsError> {
+        let lock = self.lock().and_then(|lock| self.load().map(|_| lock));
+        let before = self.known_hosts.0.clone();
+        let res = f(&mut self.known_hosts);
+        let after = &self.known_hosts.0;
+        for host in before.keys().filter(|host| !after.contains_key(*host)) {
+            self.pending.insert(host.clone(), None);
+        }
+        for (host, known_host) in after {
+            if before.get(host) != Some(known_host) {
+                self.pending.insert(host.clone(), Some(known_host.clone()));
+            }
+        }
+        let _lock = lock?;
+        self.save()?;
+        Ok(res)
+    }
+}
+
+fn sibling(path: &Path, ext: &str) -> PathBuf {
+    let mut path = path.to_owned().into_os_string();
+    path.push(ext);
+    PathBuf::from(path)
+}
+
+/// Modification time and size of the file at `path`, `None` if it doesn't exist
+fn stamp(path: &Path) -> Result<Option<(SystemTime, u64)>, KnownHostsError> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some((metadata.modified()?, metadata.len()))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Opens the lock file at `path` and blocks until it's exclusively locked. The lock is
+/// released when the returned file is dropped
+fn lock_file(path: &Path) -> io::Result<fs::File> {
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        loop {
+            // SAFETY: the descriptor stays open as long as `lock`
+            if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                break;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+    Ok(lock)
+}
+
+fn parse(text: &str) -> (KnownHostsMap, bool) {
+    let mut known_hosts = KnownHostsMap::new();
+    let mut migrated = false;
+    let now = unix_now();
+    for line in text.lines() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (host, known_host) = match parts[..] {
+            [host, sha] => {
+                migrated = true;
+                let known_host = KnownHost {
+                    fingerprint: sha.to_owned(),
+                    spki_fingerprint: None,
+                    first_seen: now,
+                    expires: None,
+                    pinned: false,
+                };
+                (host, known_host)
+            }
+            [host, sha, first_seen, expires, spki, ref flags @ ..] => {
+                let known_host = KnownHost {
+                    fingerprint: sha.to_owned(),
+                    spki_fingerprint: Some(spki).filter(|s| *s != "-").map(str::to_owned),
+                    first_seen: first_seen.parse().unwrap_or(now),
+                    expires: expires.parse().ok(),
+                    pinned: flags.contains(&"pinned"),
+                };
+                (host, known_host)
+            }
+            _ => continue,
+        };
+        let key = match migrate_key(host) {
+            Some(key) => {
+                migrated = true;
+                key
             }
-        });
-        let file = bf.into_inner();
-        Self { file, known_hosts }
+            None => host.to_owned(),
+        };
+        known_hosts.0.insert(key, known_host);
     }
+    (known_hosts, migrated)
+}
+
+fn format_line(host: &str, known_host: &KnownHost) -> String {
+    format!(
+        "{} {} {} {} {}{}\n",
+        host,
+        known_host.fingerprint,
+        known_host.first_seen,
+        known_host
+            .expires
+            .map_or("-".to_owned(), |expires| expires.to_string()),
+        known_host.spki_fingerprint.as_deref().unwrap_or("-"),
+        if known_host.pinned { " pinned" } else { "" },
+    )
 }
 
 impl KnownHostsRepo for KnownHostsFile {
-    fn get(&self, host: &str) -> Option<&str> {
+    fn get(&self, host: &str) -> Option<&KnownHost> {
         self.known_hosts.get(host)
     }
 
-    fn insert(&mut self, host: &str, sha: &str) -> bool {
-        let new = self.known_hosts.insert(host, sha);
-        self.file
-            .write_all(format!("{host} {sha}\n").as_bytes())
+    fn insert(&mut self, host: &str, known_host: KnownHost) -> Result<bool, KnownHostsError> {
+        self.update(|known_hosts| known_hosts.0.insert(host.to_owned(), known_host).is_none())
+    }
+
+    fn remove(&mut self, host: &str) -> Result<bool, KnownHostsError> {
+        self.update(|known_hosts| known_hosts.0.remove(host).is_some())
+    }
+
+    fn values(&self) -> HashMap<String, KnownHost> {
+        self.known_hosts.values()
+    }
+
+    fn refresh(&mut self) -> Result<(), KnownHostsError> {
+        if self.stamp_on_disk()? != self.stamp {
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    /// Adds all the hosts with a single write of the file
+    fn insert_new(
+        &mut self,
+        known_hosts: Vec<(String, KnownHost)>,
+    ) -> Result<usize, KnownHostsError> {
+        self.update(|map| {
+            let mut added = 0;
+            for (host, known_host) in known_hosts {
+                if let hash_map::Entry::Vacant(entry) = map.0.entry(host) {
+                    entry.insert(known_host);
+                    added += 1;
+                }
+            }
+            added
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 86400;
+    /// 2025-01-01, while the test certificates are valid
+    const NOW: i64 = 1735689600;
+
+    /// Like [`validate_der`], at [`NOW`] instead of the current time
+    fn validate_der_now(
+        repo: &mut impl KnownHostsRepo,
+        host: &str,
+        port: u16,
+        der: &[u8],
+    ) -> Result<(), CertificateError> {
+        repo.refresh().unwrap();
+        validate_at(repo, &host_key(host, port), der, NOW)
+    }
+
+    /// DER encoded certificate valid from `year` to 2030, with the public key of `key_pair`
+    fn certificate(key_pair: &rcgen::KeyPair, year: i32) -> Vec<u8> {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+        params.not_before = rcgen::date_time_ymd(year, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2030, 1, 1);
+        params.self_signed(key_pair).unwrap().der().to_vec()
+    }
+
+    #[test]
+    fn rotation() {
+        let now = NOW;
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = certificate(&key_pair, 2020);
+        let mut repo = KnownHostsMap::new();
+
+        assert_eq!(validate_at(&mut repo, "localhost", &cert, now), Ok(()));
+        let known = repo.get("localhost").unwrap().clone();
+        assert_eq!(known.first_seen, now);
+        assert_eq!(known.expires, Some(1893456000));
+
+        // Renewed with the same key
+        let renewed = certificate(&key_pair, 2021);
+        assert_ne!(renewed, cert);
+        assert_eq!(validate_at(&mut repo, "localhost", &renewed, now), Ok(()));
+        assert_eq!(
+            repo.get("localhost").unwrap().fingerprint,
+            fingerprint(&renewed)
+        );
+
+        // Another key, while the known certificate is still valid
+        let other = certificate(&rcgen::KeyPair::generate().unwrap(), 2020);
+        assert_eq!(
+            validate_at(&mut repo, "localhost", &other, now),
+            Err(CertificateError::BadIdentity)
+        );
+
+        // Another key, after the known certificate expired. The new one is expired too, so
+        // it isn't remembered
+        let later = 1893456000 + DAY;
+        assert_eq!(
+            validate_at(&mut repo, "localhost", &other, later),
+            Err(CertificateError::Expired)
+        );
+        assert_eq!(
+            repo.get("localhost").unwrap().fingerprint,
+            fingerprint(&renewed)
+        );
+
+        // Neither are the certificates of new hosts outside of their validity period
+        assert_eq!(
+            validate_at(&mut repo, "expired.example", &other, later),
+            Err(CertificateError::Expired)
+        );
+        let future = certificate(&key_pair, 2026);
+        assert_eq!(
+            validate_at(&mut repo, "future.example", &future, now),
+            Err(CertificateError::NotActivated)
+        );
+        assert_eq!(repo.get("expired.example"), None);
+        assert_eq!(repo.get("future.example"), None);
+    }
+
+    #[test]
+    fn file_migration() {
+        let path = std::env::temp_dir().join(format!("gemini-known-hosts-{}", std::process::id()));
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = certificate(&key_pair, 2020);
+        std::fs::write(&path, format!("localhost {}\n", fingerprint(&cert))).unwrap();
+        let key = "localhost:1965";
+
+        let mut repo = KnownHostsFile::open(&path).unwrap();
+        let migrated = std::fs::read_to_string(&path).unwrap();
+        assert!(migrated.starts_with(&format!("{} {} ", key, fingerprint(&cert))));
+        assert!(migrated.trim_end().ends_with(" - -"));
+
+        // The migrated entry is completed the next time the certificate is seen
+        assert_eq!(validate_at(&mut repo, key, &cert, NOW), Ok(()));
+        let repo = KnownHostsFile::open(&path).unwrap();
+        let known = repo.get(key).unwrap();
+        assert_eq!(known.expires, Some(1893456000));
+        assert!(known.spki_fingerprint.is_some());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(repo.sibling(".lock")).unwrap();
+    }
+
+    #[test]
+    fn shared_file() {
+        let path =
+            std::env::temp_dir().join(format!("gemini-known-hosts-shared-{}", std::process::id()));
+        let first = certificate(&rcgen::KeyPair::generate().unwrap(), 2020);
+        let second = certificate(&rcgen::KeyPair::generate().unwrap(), 2020);
+
+        // Two windows or processes using the same file
+        let mut a = KnownHostsFile::open(&path).unwrap();
+        let mut b = KnownHostsFile::open(&path).unwrap();
+        assert_eq!(validate_der_now(&mut a, "localhost", 1965, &first), Ok(()));
+        assert_eq!(validate_der_now(&mut b, "localhost", 1966, &second), Ok(()));
+        assert_eq!(b.values().len(), 2);
+
+        // The entry saved by the other instance is seen without reopening the file
+        assert_eq!(
+            validate_der_now(&mut b, "localhost", 1965, &second),
+            Err(CertificateError::BadIdentity)
+        );
+        assert!(b.remove("localhost:1966").unwrap());
+        a.refresh().unwrap();
+        assert_eq!(a.values().len(), 1);
+
+        assert!(!a.sibling(".tmp").exists());
+        let stored = KnownHostsFile::open(&path).unwrap();
+        assert!(stored.get("localhost:1965").is_some());
+
+        // A batch only adds the unknown hosts
+        let batch = vec![
+            ("localhost:1965".to_owned(), KnownHost::from_der(&second, 0)),
+            ("localhost:1967".to_owned(), KnownHost::from_der(&second, 0)),
+            ("localhost:1967".to_owned(), KnownHost::from_der(&first, 0)),
+        ];
+        assert_eq!(b.insert_new(batch).unwrap(), 1);
+        a.refresh().unwrap();
+        assert_eq!(
+            a.get("localhost:1965").unwrap().fingerprint,
+            fingerprint(&first)
+        );
+        assert_eq!(
+            a.get("localhost:1967").unwrap().fingerprint,
+            fingerprint(&second)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(a.sibling(".lock")).unwrap();
+    }
+
+    #[test]
+    fn unsaved_changes() {
+        let path =
+            std::env::temp_dir().join(format!("gemini-known-hosts-unsaved-{}", std::process::id()));
+        let cert = certificate(&rcgen::KeyPair::generate().unwrap(), 2020);
+        let mut repo = KnownHostsFile::open(&path).unwrap();
+        repo.insert("removed:1965", KnownHost::from_der(&cert, 0))
             .unwrap();
-        new
+
+        // The temporary file can't be created while a directory has its name
+        std::fs::create_dir(repo.sibling(".tmp")).unwrap();
+        assert!(repo
+            .insert("unsaved:1965", KnownHost::from_der(&cert, 0))
+            .is_err());
+        assert!(repo.remove("removed:1965").is_err());
+        assert!(repo.get("unsaved:1965").is_some());
+        assert!(repo.get("removed:1965").is_none());
+        std::fs::remove_dir(repo.sibling(".tmp")).unwrap();
+
+        // Another process saves a host in the meantime: the unsaved changes are kept on top
+        let added = format_line("added:1965", &KnownHost::from_der(&cert, 0));
+        let text = std::fs::read_to_string(&path).unwrap() + &added;
+        std::fs::write(&path, text).unwrap();
+        repo.insert("saved:1965", KnownHost::from_der(&cert, 0))
+            .unwrap();
+
+        let stored = KnownHostsFile::open(&path).unwrap();
+        let mut hosts: Vec<_> = stored.values().into_keys().collect();
+        hosts.sort();
+        assert_eq!(hosts, ["added:1965", "saved:1965", "unsaved:1965"]);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(repo.sibling(".lock")).unwrap();
+    }
+
+    #[test]
+    fn pinning() {
+        let now = NOW;
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = certificate(&key_pair, 2020);
+        let mut repo = KnownHostsMap::new();
+        assert_eq!(validate_at(&mut repo, "localhost", &cert, now), Ok(()));
+        let known = repo.get("localhost").unwrap().clone();
+        repo.insert(
+            "localhost",
+            KnownHost {
+                pinned: true,
+                ..known
+            },
+        )
+        .unwrap();
+
+        // Renewed with the same key, or after the expiration
+        let renewed = certificate(&key_pair, 2021);
+        assert_eq!(
+            validate_at(&mut repo, "localhost", &renewed, now),
+            Err(CertificateError::BadIdentity)
+        );
+        assert_eq!(
+            validate_at(&mut repo, "localhost", &renewed, 1893456000 + DAY),
+            Err(CertificateError::BadIdentity)
+        );
+        assert_eq!(validate_at(&mut repo, "localhost", &cert, now), Ok(()));
+
+        let line = format_line("localhost:1965", repo.get("localhost").unwrap());
+        assert!(line.ends_with(" pinned\n"));
+        let (parsed, _) = parse(&line);
+        assert!(parsed.get("localhost:1965").unwrap().pinned);
+    }
+
+    #[test]
+    fn ca_rotation_by_default() {
+        let now = NOW;
+        let cert = certificate(&rcgen::KeyPair::generate().unwrap(), 2020);
+        let rotated = certificate(&rcgen::KeyPair::generate().unwrap(), 2021);
+        let mut repo = KnownHostsMap::new();
+        let mode = ValidationMode::default();
+        assert_eq!(
+            validate_chain_at(&mut repo, "localhost", &cert, Ok(()), mode, now),
+            Ok(())
+        );
+        // A new key, signed by a trusted authority
+        assert_eq!(
+            validate_chain_at(&mut repo, "localhost", &rotated, Ok(()), mode, now),
+            Ok(())
+        );
+        assert_eq!(
+            repo.get("localhost").unwrap().fingerprint,
+            fingerprint(&rotated)
+        );
     }
 
-    fn remove(&mut self, host: &str) -> bool {
-        let r = self.known_hosts.remove(host);
-        self.file.set_len(0).unwrap();
-        self.file.rewind().unwrap();
-        for (host, sha) in self.known_hosts.values() {
-            self.file
-                .write_all(format!("{host} {sha}\n").as_bytes())
-                .unwrap();
+    #[test]
+    fn validation_modes() {
+        let now = NOW;
+        let cert = certificate(&rcgen::KeyPair::generate().unwrap(), 2020);
+        let other = certificate(&rcgen::KeyPair::generate().unwrap(), 2020);
+        let untrusted = Err(CertificateError::UnknownAuthority);
+        let validate = |repo: &mut KnownHostsMap, der, chain, mode| {
+            validate_chain_at(repo, "localhost", der, chain, mode, now)
+        };
+
+        // A rotation signed by a trusted authority
+        for mode in [ValidationMode::CaOrTofu, ValidationMode::Ca] {
+            let mut repo = KnownHostsMap::new();
+            assert_eq!(validate(&mut repo, &cert, Ok(()), mode), Ok(()));
+            assert_eq!(validate(&mut repo, &other, Ok(()), mode), Ok(()));
+            assert_eq!(
+                repo.get("localhost").unwrap().fingerprint,
+                fingerprint(&other)
+            );
+            // Then back to an untrusted certificate
+            let expected = match mode {
+                ValidationMode::Ca => untrusted,
+                _ => Err(CertificateError::BadIdentity),
+            };
+            assert_eq!(validate(&mut repo, &cert, untrusted, mode), expected);
         }
-        r
+
+        let mut repo = KnownHostsMap::new();
+        assert_eq!(
+            validate(&mut repo, &cert, Ok(()), ValidationMode::Tofu),
+            Ok(())
+        );
+        assert_eq!(
+            validate(&mut repo, &other, Ok(()), ValidationMode::Tofu),
+            Err(CertificateError::BadIdentity)
+        );
+        assert_eq!(
+            validate(&mut repo, &cert, untrusted, ValidationMode::CaOrTofu),
+            Ok(())
+        );
+
+        // A pinned certificate isn't replaced
+        let known = repo.get("localhost").unwrap().clone();
+        repo.insert(
+            "localhost",
+            KnownHost {
+                pinned: true,
+                ..known
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            validate(&mut repo, &other, Ok(()), ValidationMode::CaOrTofu),
+            Err(CertificateError::BadIdentity)
+        );
+
+        assert_eq!("ca-or-tofu".parse(), Ok(ValidationMode::CaOrTofu));
+        assert!("ca-only".parse::<ValidationMode>().is_err());
     }
 
-    fn values(&self) -> HashMap<String, String> {
-        self.known_hosts.values()
+    #[test]
+    fn certificate_info() {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = certificate(&key_pair, 2020);
+        let info = CertificateInfo::from_der(&cert);
+        assert_eq!(info.fingerprint, fingerprint(&cert));
+        assert_eq!(info.not_before, Some(1577836800));
+        assert_eq!(info.not_after, Some(1893456000));
+        assert!(info.subject.is_some());
+
+        let info = CertificateInfo::from_der(&cert[..100]);
+        assert_eq!(info.fingerprint, fingerprint(&cert[..100]));
+        assert_eq!(info.spki_fingerprint, None);
+        assert_eq!(info.subject, None);
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(host_key("example.org", 1965), "example.org:1965");
+        assert_eq!(host_key("::1", 1966), "[::1]:1966");
+        assert_eq!(host_key("[::1]", 1966), "[::1]:1966");
+
+        assert_eq!(
+            migrate_key("example.org"),
+            Some("example.org:1965".to_owned())
+        );
+        assert_eq!(migrate_key("::1"), Some("[::1]:1965".to_owned()));
+        assert_eq!(migrate_key("[::1]"), Some("[::1]:1965".to_owned()));
+        assert_eq!(migrate_key("example.org:1966"), None);
+        assert_eq!(migrate_key("[::1]:1966"), None);
+
+        // Two capsules on the same host
+        let mut repo = KnownHostsMap::new();
+        let first = certificate(&rcgen::KeyPair::generate().unwrap(), 2020);
+        let second = certificate(&rcgen::KeyPair::generate().unwrap(), 2020);
+        assert_eq!(
+            validate_der_now(&mut repo, "localhost", 1965, &first),
+            Ok(())
+        );
+        assert_eq!(
+            validate_der_now(&mut repo, "localhost", 1966, &second),
+            Ok(())
+        );
+        assert_eq!(repo.values().len(), 2);
     }
 }
@@ -0,0 +1,406 @@
+//! Conversion of the known hosts from and to the TOFU stores of other gemini clients:
+//!
+//! - Lagrange, `trusted.2.txt`: `host[;port] valid_until spki_fingerprint` lines.
+//! - Amfora, `tofu.toml`: `"host/with/slashes[:port]" = "FINGERPRINT"` keys, with the
+//!   expiration date under the same key followed by `/expiry`.
+//! - AV-98, `tofu.db`: the `cert_cache` table of a sqlite database. Only importing is supported,
+//!   since AV-98 stores a certificate for each ip address of a host, which isn't known here.
+//!
+//! The imported hosts never replace the known ones.
+
+use std::fmt::Write;
+
+use super::{host_key, unix_now, KnownHost, KnownHostsError, KnownHostsRepo};
+use crate::der;
+
+const DEFAULT_PORT: u16 = 1965;
+
+#[derive(Debug, thiserror::Error)]
+pub enum InteropError {
+    #[error("Invalid entry at line {0}")]
+    InvalidLine(usize),
+    #[error(transparent)]
+    KnownHosts(#[from] KnownHostsError),
+    #[cfg(feature = "av98")]
+    #[error("Sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Host, without the brackets of an IPv6 address, and port of a known hosts key
+fn split_key(key: &str) -> Option<(&str, u16)> {
+    let (host, port) = key.rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Some((host, port.parse().ok()?))
+}
+
+/// Imports the hosts of a Lagrange `trusted.2.txt` file, returning the number of hosts added.
+///
+/// Lagrange only stores the fingerprint of the public key, the certificate is stored the
+/// first time it's seen
+pub fn import_lagrange(
+    repo: &mut (impl KnownHostsRepo + ?Sized),
+    text: &str,
+) -> Result<usize, InteropError> {
+    let now = unix_now();
+    let mut known_hosts = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let [host, valid_until, spki] = parts[..] else {
+            return Err(InteropError::InvalidLine(i + 1));
+        };
+        let (host, port) = match host.split_once(';') {
+            Some((host, port)) => (host, port.parse().ok()),
+            None => (host, Some(DEFAULT_PORT)),
+        };
+        let (Some(port), Ok(valid_until)) = (port, valid_until.parse()) else {
+            return Err(InteropError::InvalidLine(i + 1));
+        };
+        let known_host = KnownHost {
+            fingerprint: "-".to_owned(),
+            spki_fingerprint: Some(spki.to_ascii_lowercase()),
+            first_seen: now,
+            expires: Some(valid_until),
+            pinned: false,
+        };
+        known_hosts.push((host_key(host, port), known_host));
+    }
+    Ok(repo.insert_new(known_hosts)?)
+}
+
+/// Known hosts in the format of a Lagrange `trusted.2.txt` file. The hosts without a public
+/// key fingerprint or an expiration date are left out: Lagrange accepts any certificate in
+/// place of an expired one
+pub fn export_lagrange(repo: &(impl KnownHostsRepo + ?Sized)) -> String {
+    let mut hosts: Vec<_> = repo.values().into_iter().collect();
+    hosts.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut text = String::new();
+    for (key, known_host) in hosts {
+        let (Some((host, port)), Some(spki), Some(valid_until)) = (
+            split_key(&key),
+            &known_host.spki_fingerprint,
+            known_host.expires,
+        ) else {
+            continue;
+        };
+        let host = match port {
+            DEFAULT_PORT => host.to_owned(),
+            port => format!("{};{}", host, port),
+        };
+        writeln!(text, "{} {} {}", host, valid_until, spki).unwrap();
+    }
+    text
+}
+
+/// Imports the hosts of an Amfora `tofu.toml` file, returning the number of hosts added
+pub fn import_amfora(
+    repo: &mut (impl KnownHostsRepo + ?Sized),
+    text: &str,
+) -> Result<usize, InteropError> {
+    let mut fingerprints = vec![];
+    let mut expirations = std::collections::HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or(InteropError::InvalidLine(i + 1))?;
+        let unquote = |s: &str| {
+            let s = s.trim();
+            s.strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .unwrap_or(s)
+                .to_owned()
+        };
+        let (key, value) = (unquote(key), unquote(value));
+        match key.strip_suffix("/expiry") {
+            Some(key) => {
+                let expiry = parse_datetime(&value).ok_or(InteropError::InvalidLine(i + 1))?;
+                expirations.insert(key.to_owned(), expiry);
+            }
+            None => fingerprints.push((key, value.to_ascii_lowercase())),
+        }
+    }
+
+    let now = unix_now();
+    let mut known_hosts = Vec::new();
+    for (key, fingerprint) in fingerprints {
+        let expires = expirations.get(&key).copied();
+        // IPv6 addresses aren't followed by a port
+        let (host, port) = match key.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') => {
+                (host, port.parse().unwrap_or(DEFAULT_PORT))
+            }
+            _ => (key.as_str(), DEFAULT_PORT),
+        };
+        let known_host = KnownHost {
+            fingerprint,
+            spki_fingerprint: None,
+            first_seen: now,
+            expires,
+            pinned: false,
+        };
+        known_hosts.push((host_key(&host.replace('/', "."), port), known_host));
+    }
+    Ok(repo.insert_new(known_hosts)?)
+}
+
+/// Known hosts in the format of an Amfora `tofu.toml` file. The imported Lagrange hosts,
+/// without a certificate fingerprint, are left out
+pub fn export_amfora(repo: &(impl KnownHostsRepo + ?Sized)) -> String {
+    let mut hosts: Vec<_> = repo.values().into_iter().collect();
+    hosts.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut text = String::new();
+    for (key, known_host) in hosts {
+        let Some((host, port)) = split_key(&key) else {
+            continue;
+        };
+        if known_host.fingerprint == "-" {
+            continue;
+        }
+        let mut key = host.replace('.', "/");
+        if port != DEFAULT_PORT {
+            write!(key, ":{}", port).unwrap();
+        }
+        writeln!(
+            text,
+            "\"{}\" = \"{}\"",
+            key,
+            known_host.fingerprint.to_ascii_uppercase()
+        )
+        .unwrap();
+        if let Some(expires) = known_host.expires {
+            writeln!(text, "\"{}/expiry\" = {}", key, format_datetime(expires)).unwrap();
+        }
+    }
+    text
+}
+
+/// Imports the hosts of an AV-98 `tofu.db` database, returning the number of hosts added.
+/// When a host has several certificates, the one seen last is kept
+#[cfg(feature = "av98")]
+pub fn import_av98(
+    repo: &mut (impl KnownHostsRepo + ?Sized),
+    path: &std::path::Path,
+) -> Result<usize, InteropError> {
+    let db =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut statement =
+        db.prepare("SELECT hostname, fingerprint, first_seen FROM cert_cache ORDER BY last_seen")?;
+    let rows = statement.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+        ))
+    })?;
+
+    let mut hosts = std::collections::HashMap::new();
+    for row in rows {
+        let (host, fingerprint, first_seen) = row?;
+        hosts.insert(host, (fingerprint, first_seen));
+    }
+
+    let now = unix_now();
+    let mut known_hosts = Vec::new();
+    for (host, (fingerprint, first_seen)) in hosts {
+        let known_host = KnownHost {
+            fingerprint: fingerprint.to_ascii_lowercase(),
+            spki_fingerprint: None,
+            first_seen: first_seen
+                .as_deref()
+                .and_then(parse_datetime)
+                .unwrap_or(now),
+            expires: None,
+            pinned: false,
+        };
+        known_hosts.push((host_key(&host, DEFAULT_PORT), known_host));
+    }
+    Ok(repo.insert_new(known_hosts)?)
+}
+
+/// Unix time of a date as `2024-01-31T12:00:00Z`, `2024-01-31T14:00:00+02:00` or
+/// `2024-01-31 12:00:00.123456`, assumed to be in UTC without an offset
+fn parse_datetime(s: &str) -> Option<i64> {
+    let (date, time) = s.trim().split_once(['T', ' '])?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    let (time, offset) = match time.strip_suffix('Z') {
+        Some(time) => (time, 0),
+        None => match time.rfind(['+', '-']) {
+            Some(i) => {
+                let (hours, minutes) = time[i + 1..].split_once(':')?;
+                let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+                let sign = if time[i..].starts_with('-') { -1 } else { 1 };
+                (&time[..i], sign * offset)
+            }
+            None => (time, 0),
+        },
+    };
+    // The fractions of a second are ignored
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute) = (time.next()??, time.next()??);
+    let second = time.next().flatten().unwrap_or(0);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(
+        der::days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second
+            - offset,
+    )
+}
+
+/// Formats a unix time as `2024-01-31T12:00:00Z`
+fn format_datetime(unix: i64) -> String {
+    let (days, seconds) = (unix.div_euclid(86400), unix.rem_euclid(86400));
+
+    // Inverse of `der::days_from_civil`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::known_hosts::KnownHostsMap;
+
+    fn known_host(fingerprint: &str, spki: Option<&str>) -> KnownHost {
+        KnownHost {
+            fingerprint: fingerprint.to_owned(),
+            spki_fingerprint: spki.map(str::to_owned),
+            first_seen: 0,
+            expires: Some(1893456000),
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn datetimes() {
+        assert_eq!(parse_datetime("2030-01-01T00:00:00Z"), Some(1893456000));
+        assert_eq!(
+            parse_datetime("2030-01-01T02:00:00+02:00"),
+            Some(1893456000)
+        );
+        assert_eq!(
+            parse_datetime("2030-01-01 00:00:00.123456"),
+            Some(1893456000)
+        );
+        assert_eq!(parse_datetime("2030-13-01T00:00:00Z"), None);
+        assert_eq!(format_datetime(1893456000), "2030-01-01T00:00:00Z");
+        assert_eq!(format_datetime(951825599), "2000-02-29T11:59:59Z");
+    }
+
+    #[test]
+    fn lagrange() {
+        let text = "example.org 1893456000 AB01\nexample.org;1966 1893456000 cd02\n";
+        let mut repo = KnownHostsMap::new();
+        repo.insert("example.org:1965", known_host("ef03", Some("ef04")))
+            .unwrap();
+        assert_eq!(import_lagrange(&mut repo, text).unwrap(), 1);
+        // The known host isn't replaced
+        assert_eq!(
+            repo.get("example.org:1965").unwrap().fingerprint,
+            "ef03".to_owned()
+        );
+        let imported = repo.get("example.org:1966").unwrap();
+        assert_eq!(imported.spki_fingerprint.as_deref(), Some("cd02"));
+        assert_eq!(imported.expires, Some(1893456000));
+
+        assert_eq!(
+            export_lagrange(&repo),
+            "example.org 1893456000 ef04\nexample.org;1966 1893456000 cd02\n"
+        );
+        assert!(matches!(
+            import_lagrange(&mut repo, "example.org\n"),
+            Err(InteropError::InvalidLine(1))
+        ));
+
+        // The hosts with an unknown expiration date aren't exported as expired
+        let unknown_expiry = KnownHost {
+            expires: None,
+            ..known_host("ef05", Some("ef06"))
+        };
+        repo.insert("example.org:1967", unknown_expiry).unwrap();
+        let exported = export_lagrange(&repo);
+        let mut imported = KnownHostsMap::new();
+        assert_eq!(import_lagrange(&mut imported, &exported).unwrap(), 2);
+        assert_eq!(imported.get("example.org:1967"), None);
+        assert!(imported
+            .values()
+            .values()
+            .all(|known_host| known_host.expires == Some(1893456000)));
+    }
+
+    #[test]
+    fn amfora() {
+        let text = r#""example/org" = "AB01"
+"example/org/expiry" = 2030-01-01T00:00:00Z
+"example/org:1966" = "CD02"
+"#;
+        let mut repo = KnownHostsMap::new();
+        assert_eq!(import_amfora(&mut repo, text).unwrap(), 2);
+        let imported = repo.get("example.org:1965").unwrap();
+        assert_eq!(imported.fingerprint, "ab01");
+        assert_eq!(imported.expires, Some(1893456000));
+        assert_eq!(repo.get("example.org:1966").unwrap().expires, None);
+
+        // Lagrange hosts, without a certificate fingerprint, are left out
+        repo.insert("[::1]:1965", known_host("-", Some("ef04")))
+            .unwrap();
+        let exported = export_amfora(&repo);
+        assert_eq!(exported, text);
+        let mut other = KnownHostsMap::new();
+        assert_eq!(import_amfora(&mut other, &exported).unwrap(), 2);
+    }
+
+    #[cfg(feature = "av98")]
+    #[test]
+    fn av98() {
+        let path = std::env::temp_dir().join(format!("gemini-av98-{}.db", std::process::id()));
+        let db = rusqlite::Connection::open(&path).unwrap();
+        db.execute_batch(
+            "CREATE TABLE cert_cache (hostname text, address text, fingerprint text,
+                first_seen date, last_seen date, count integer);
+            INSERT INTO cert_cache VALUES
+                ('example.org', '10.0.0.1', 'AB01', '2020-01-01 00:00:00.5', '2021-01-01', 1),
+                ('example.org', '10.0.0.1', 'CD02', '2021-01-02 00:00:00', '2022-01-01', 1);",
+        )
+        .unwrap();
+        drop(db);
+
+        let mut repo = KnownHostsMap::new();
+        assert_eq!(import_av98(&mut repo, &path).unwrap(), 1);
+        let imported = repo.get("example.org:1965").unwrap();
+        assert_eq!(imported.fingerprint, "cd02");
+        assert_eq!(imported.first_seen, 1609545600);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+//! Certificates trusted by the user despite their errors
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::KnownHostsError;
+
+/// Certificate accepted for a host whatever its errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustOverride {
+    /// Sha256 of the DER encoded certificate
+    pub fingerprint: String,
+    /// Unix time after which the certificate isn't trusted anymore, `None` to always trust it
+    pub until: Option<i64>,
+}
+
+impl TrustOverride {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.until.is_some_and(|until| until < now)
+    }
+}
+
+/// Trust overrides by host key, as returned by [`super::host_key`].
+///
+/// When opened from a file, each override is saved on its own line:
+/// `host fingerprint until`, with `-` for the overrides that never expire. The expired
+/// overrides are dropped when the file is loaded.
+///
+/// Like [`super::KnownHostsFile`], each change is made while holding a lock on a `.lock` file
+/// next to the stored one, on top of the overrides currently stored, and written to a
+/// temporary file renamed over the old one.
+#[derive(Debug, Clone, Default)]
+pub struct TrustOverrides {
+    path: Option<PathBuf>,
+    overrides: BTreeMap<String, TrustOverride>,
+    /// Modification time and size of the file when it was last read or written
+    stamp: Option<(SystemTime, u64)>,
+    /// Changes that couldn't be saved yet, applied again on top of the stored overrides.
+    /// `None` for a removed override
+    pending: BTreeMap<String, Option<TrustOverride>>,
+}
+
+impl TrustOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn open(path: &Path) -> Result<Self, KnownHostsError> {
+        Self::open_at(path, super::unix_now())
+    }
+    fn open_at(path: &Path, now: i64) -> Result<Self, KnownHostsError> {
+        let mut this = Self {
+            path: Some(path.to_owned()),
+            ..Self::default()
+        };
+        this.reload(now)?;
+        Ok(this)
+    }
+    pub fn get(&self, host: &str) -> Option<&TrustOverride> {
+        self.overrides.get(host)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &TrustOverride)> {
+        self.overrides.iter().map(|(k, v)| (k.as_str(), v))
+    }
+    /// Whether the certificate with `fingerprint` is trusted for `host` at `now`
+    pub fn allows(&self, host: &str, fingerprint: &str, now: i64) -> bool {
+        self.get(host).is_some_and(|trust_override| {
+            trust_override.fingerprint == fingerprint && !trust_override.is_expired(now)
+        })
+    }
+    /// Trusts a certificate for `host`, replacing its previous override. If it can't be
+    /// saved, the override is only kept in memory
+    pub fn insert(
+        &mut self,
+        host: &str,
+        trust_override: TrustOverride,
+    ) -> Result<(), KnownHostsError> {
+        self.update(super::unix_now(), |overrides| {
+            overrides.insert(host.to_owned(), trust_override);
+        })
+    }
+    pub fn remove(&mut self, host: &str) -> Result<bool, KnownHostsError> {
+        self.update(super::unix_now(), |overrides| {
+            overrides.remove(host).is_some()
+        })
+    }
+    /// Reloads the overrides if they were changed by another process
+    pub fn refresh(&mut self) -> Result<(), KnownHostsError> {
+        self.refresh_at(super::unix_now())
+    }
+    fn refresh_at(&mut self, now: i64) -> Result<(), KnownHostsError> {
+        match &self.path {
+            Some(path) if super::stamp(path)? != self.stamp => self.reload(now),
+            _ => Ok(()),
+        }
+    }
+    /// Exclusive lock on the file, released when the returned file is dropped
+    fn lock(&self) -> Result<Option<fs::File>, KnownHostsError> {
+        match &self.path {
+            Some(path) => Ok(Some(super::lock_file(&super::sibling(path, ".lock"))?)),
+            None => Ok(None),
+        }
+    }
+    /// Replaces the overrides with the stored ones still valid at `now` and the pending changes.
+    /// Must be called holding the lock
+    fn load(&mut self, now: i64) -> Result<(), KnownHostsError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut overrides = BTreeMap::new();
+        for line in text.lines() {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let [host, fingerprint, until] = parts[..] else {
+                continue;
+            };
+            let trust_override = TrustOverride {
+                fingerprint: fingerprint.to_owned(),
+                until: until.parse().ok(),
+            };
+            if !trust_override.is_expired(now) {
+                overrides.insert(host.to_owned(), trust_override);
+            }
+        }
+        for (host, change) in &self.pending {
+            match change {
+                Some(trust_override) => overrides.insert(host.clone(), trust_override.clone()),
+                None => overrides.remove(host),
+            };
+        }
+        self.overrides = overrides;
+        self.stamp = super::stamp(path)?;
+        Ok(())
+    }
+    /// Atomically replaces the stored overrides. Must be called holding the lock
+    fn save(&mut self) -> Result<(), KnownHostsError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp_path = super::sibling(path, ".tmp");
+        let mut tmp = fs::File::create(&tmp_path)?;
+        for (host, trust_override) in &self.overrides {
+            let until = trust_override
+                .until
+                .map_or("-".to_owned(), |until| until.to_string());
+            writeln!(tmp, "{} {} {}", host, trust_override.fingerprint, until)?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        self.stamp = super::stamp(path)?;
+        self.pending.clear();
+        Ok(())
+    }
+    fn reload(&mut self, now: i64) -> Result<(), KnownHostsError> {
+        let _lock = self.lock()?;
+        self.load(now)?;
+        if !self.pending.is_empty() {
+            self.save()?;
+        }
+        Ok(())
+    }
+    /// Applies `f` to the stored overrides still valid at `now`, including the ones added by
+    /// other processes. If they can't be saved, the change is only kept in memory, and saved
+    /// again with the next change
+    fn update<T>(
+        &mut self,
+        now: i64,
+        f: impl FnOnce(&mut BTreeMap<String, TrustOverride>) -> T,
+    ) -> Result<T, KnownHostsError> {
+        let lock = self.lock().and_then(|lock| self.load(now).map(|_| lock));
+        let before = self.overrides.clone();
+        let res = f(&mut self.overrides);
+        let after = &self.overrides;
+        for host in before.keys().filter(|host| !after.contains_key(*host)) {
+            self.pending.insert(host.clone(), None);
+        }
+        for (host, trust_override) in after {
+            if before.get(host) != Some(trust_override) {
+                self.pending
+                    .insert(host.clone(), Some(trust_override.clone()));
+            }
+        }
+        let _lock = lock?;
+        self.save()?;
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persisted_overrides() -> Result<(), KnownHostsError> {
+        let path = std::env::temp_dir().join(format!("gemini-overrides-{}", std::process::id()));
+        let now = super::super::unix_now();
+
+        let mut overrides = TrustOverrides::open_at(&path, now)?;
+        let always = TrustOverride {
+            fingerprint: "aa".to_owned(),
+            until: None,
+        };
+        overrides.insert("example.org:1965", always.clone())?;
+        let month = TrustOverride {
+            fingerprint: "bb".to_owned(),
+            until: Some(now + 30 * 86400),
+        };
+        overrides.insert("localhost:1965", month)?;
+        assert!(overrides.allows("localhost:1965", "bb", now));
+        assert!(!overrides.allows("localhost:1965", "aa", now));
+        assert!(!overrides.allows("localhost:1965", "bb", now + 31 * 86400));
+
+        let overrides = TrustOverrides::open_at(&path, now)?;
+        assert_eq!(overrides.get("example.org:1965"), Some(&always));
+        assert!(overrides.allows("localhost:1965", "bb", now));
+
+        // The expired overrides are dropped
+        let mut overrides = TrustOverrides::open_at(&path, now + 31 * 86400)?;
+        assert_eq!(overrides.get("localhost:1965"), None);
+        assert!(overrides.remove("example.org:1965")?);
+        let overrides = TrustOverrides::open_at(&path, now)?;
+        assert_eq!(overrides.get("example.org:1965"), None);
+        assert!(overrides.allows("localhost:1965", "bb", now));
+
+        fs::remove_file(&path)?;
+        fs::remove_file(super::super::sibling(&path, ".lock"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn shared_overrides() -> Result<(), KnownHostsError> {
+        let path =
+            std::env::temp_dir().join(format!("gemini-shared-overrides-{}", std::process::id()));
+        let trust_override = |fingerprint: &str| TrustOverride {
+            fingerprint: fingerprint.to_owned(),
+            until: None,
+        };
+
+        // Each change keeps the overrides saved by the other instance
+        let mut first = TrustOverrides::open(&path)?;
+        let mut second = TrustOverrides::open(&path)?;
+        first.insert("example.org:1965", trust_override("aa"))?;
+        second.insert("localhost:1965", trust_override("bb"))?;
+        assert_eq!(second.iter().count(), 2);
+        assert_eq!(TrustOverrides::open(&path)?.iter().count(), 2);
+
+        // The removals are seen once refreshed
+        second.remove("example.org:1965")?;
+        assert!(first.get("example.org:1965").is_some());
+        first.refresh()?;
+        assert_eq!(first.get("example.org:1965"), None);
+        assert_eq!(first.get("localhost:1965"), Some(&trust_override("bb")));
+
+        fs::remove_file(&path)?;
+        fs::remove_file(super::super::sibling(&path, ".lock"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn unsaved_overrides() -> Result<(), KnownHostsError> {
+        let path =
+            std::env::temp_dir().join(format!("gemini-unsaved-overrides-{}", std::process::id()));
+        let trust_override = TrustOverride {
+            fingerprint: "aa".to_owned(),
+            until: None,
+        };
+        let mut overrides = TrustOverrides::open(&path)?;
+
+        // The temporary file can't be created while a directory has its name
+        let tmp = super::super::sibling(&path, ".tmp");
+        fs::create_dir(&tmp)?;
+        assert!(overrides
+            .insert("unsaved:1965", trust_override.clone())
+            .is_err());
+        fs::remove_dir(&tmp)?;
+
+        // The change is saved with the next one, on top of the stored overrides
+        fs::write(&path, "added:1965 bb -\n")?;
+        overrides.insert("saved:1965", trust_override)?;
+        let stored = TrustOverrides::open(&path)?;
+        let hosts: Vec<_> = stored.iter().map(|(host, _)| host).collect();
+        assert_eq!(hosts, ["added:1965", "saved:1965", "unsaved:1965"]);
+
+        fs::remove_file(&path)?;
+        fs::remove_file(super::super::sibling(&path, ".lock"))?;
+        Ok(())
+    }
+}
@@ -1,6 +1,19 @@
 mod client;
+mod der;
+pub mod finger;
+pub mod gopher;
+pub mod identity;
 pub mod known_hosts;
+pub mod mime;
+pub mod nex;
 mod parser;
+pub mod proxy;
+pub mod server;
+pub mod spartan;
+#[cfg(feature = "rustls")]
+pub mod sync;
+pub mod transport;
 pub use client::*;
 pub use known_hosts::CertificateError;
+pub use mime::Mime;
 pub use parser::*;
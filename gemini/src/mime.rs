@@ -0,0 +1,171 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Status;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid mime type {0:?}")]
+pub struct InvalidMime(pub String);
+
+/// Mime type of a success response, as in `text/gemini; charset=utf-8; lang=en`.
+///
+/// The type, the subtype and the parameter names are case insensitive, and stored lowercased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mime {
+    type_: String,
+    subtype: String,
+    params: Vec<(String, String)>,
+}
+
+impl Mime {
+    /// Mime type of the responses with an empty meta
+    pub fn gemini() -> Self {
+        Self {
+            type_: "text".to_owned(),
+            subtype: "gemini".to_owned(),
+            params: vec![],
+        }
+    }
+    pub fn type_(&self) -> &str {
+        &self.type_
+    }
+    pub fn subtype(&self) -> &str {
+        &self.subtype
+    }
+    /// Type and subtype, without the parameters
+    pub fn essence(&self) -> String {
+        format!("{}/{}", self.type_, self.subtype)
+    }
+    pub fn is_text(&self) -> bool {
+        self.type_ == "text"
+    }
+    pub fn is_gemini(&self) -> bool {
+        self.type_ == "text" && self.subtype == "gemini"
+    }
+    pub fn params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+    /// Value of the parameter named `name`, if present
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    /// Charset of the text, `utf-8` when unspecified
+    pub fn charset(&self) -> &str {
+        self.param("charset").unwrap_or("utf-8")
+    }
+    /// Languages of the content, as a comma separated list of BCP47 tags
+    pub fn lang(&self) -> Option<&str> {
+        self.param("lang")
+    }
+    pub fn format(&self) -> Option<&str> {
+        self.param("format")
+    }
+}
+
+impl FromStr for Mime {
+    type Err = InvalidMime;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidMime(s.to_owned());
+        let mut parts = s.split(';');
+        let essence = parts.next().unwrap_or_default().trim();
+        let (type_, subtype) = essence.split_once('/').ok_or_else(invalid)?;
+        if !is_token(type_) || !is_token(subtype) {
+            return Err(invalid());
+        }
+
+        let mut params = vec![];
+        for param in parts {
+            let param = param.trim();
+            if param.is_empty() {
+                continue;
+            }
+            // A malformed parameter is skipped, the content stays readable without it
+            let Some((name, value)) = param.split_once('=') else {
+                continue;
+            };
+            let name = name.trim();
+            if !is_token(name) {
+                continue;
+            }
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            params.push((name.to_ascii_lowercase(), value.to_owned()));
+        }
+
+        Ok(Self {
+            type_: type_.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            params,
+        })
+    }
+}
+
+impl fmt::Display for Mime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.type_, self.subtype)?;
+        for (name, value) in &self.params {
+            write!(f, "; {}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Mime type of a response with `status` and `meta`, if it's a success. An empty meta means
+/// `text/gemini`
+pub(crate) fn from_meta(status: Status, meta: &str) -> Option<Mime> {
+    match status {
+        Status::Success(_) if meta.trim().is_empty() => Some(Mime::gemini()),
+        Status::Success(_) => meta.parse().ok(),
+        _ => None,
+    }
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let mime: Mime = "Text/Gemini; charset=ISO-8859-1; LANG=\"ar\""
+            .parse()
+            .unwrap();
+        assert!(mime.is_gemini());
+        assert_eq!(mime.essence(), "text/gemini");
+        assert_eq!(mime.charset(), "ISO-8859-1");
+        assert_eq!(mime.lang(), Some("ar"));
+        assert_eq!(mime.format(), None);
+        assert_eq!(mime.to_string(), "text/gemini; charset=ISO-8859-1; lang=ar");
+
+        let mime: Mime = "application/x-text-foo".parse().unwrap();
+        assert!(!mime.is_text());
+        assert_eq!(mime.charset(), "utf-8");
+
+        let mime: Mime = "text/plain;format=flowed;".parse().unwrap();
+        assert_eq!(mime.format(), Some("flowed"));
+
+        assert!("text".parse::<Mime>().is_err());
+        assert!("text/".parse::<Mime>().is_err());
+
+        // Only the malformed parameters are left out
+        let mime: Mime = "text/gemini; charset; lang=fr; bad name=x".parse().unwrap();
+        assert!(mime.is_gemini());
+        assert_eq!(mime.charset(), "utf-8");
+        assert_eq!(mime.lang(), Some("fr"));
+        assert_eq!(mime.params().count(), 1);
+        let mime = from_meta(Status::Success(20), "text/gemini; charset").unwrap();
+        assert!(mime.is_gemini());
+    }
+}
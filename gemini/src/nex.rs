@@ -0,0 +1,52 @@
+use futures::prelude::*;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::client::send_request;
+use crate::{Client, Error};
+
+const DEFAULT_PORT: u16 = 1900;
+
+/// Path to send to the server, without the leading slash
+fn selector(url: &Url) -> String {
+    let path = url.path().trim_start_matches('/');
+    percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
+
+/// Nex directories are the urls ending with a slash. Their listings use gemtext links
+pub fn is_directory(url: &Url) -> bool {
+    url.path().is_empty() || url.path().ends_with('/')
+}
+
+impl Client {
+    /// Fetches a nex url, returning the raw document sent by the server
+    pub async fn fetch_nex(&self, url_str: &str) -> Result<impl AsyncRead + Unpin, Error> {
+        let url = Url::parse(url_str)?;
+        if url.scheme() != "nex" {
+            return Err(Error::SchemeNotSupported);
+        }
+        let connection = self.connect_plain(&url, DEFAULT_PORT).await?;
+        let request = selector(&url) + "\r\n";
+        send_request(connection, request.into_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directories() {
+        let url = Url::parse("nex://example.org").unwrap();
+        assert!(is_directory(&url));
+        assert_eq!(selector(&url), "");
+
+        let url = Url::parse("nex://example.org/log/").unwrap();
+        assert!(is_directory(&url));
+        assert_eq!(selector(&url), "log/");
+
+        let url = Url::parse("nex://example.org/log/first%20entry.txt").unwrap();
+        assert!(!is_directory(&url));
+        assert_eq!(selector(&url), "log/first entry.txt");
+    }
+}
@@ -2,6 +2,8 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 static R_GEMINI_LINK: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"^=>\s*(?P<href>\S+)(\s+(?P<label>.+))?").unwrap());
+static R_SPARTAN_PROMPT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^=:\s*(?P<href>\S+)(\s+(?P<label>.+))?").unwrap());
 
 // See gemini://gemini.circumlunar.space/docs/cheatsheet.gmi
 
@@ -14,6 +16,8 @@ pub enum Tag {
     UnorderedList,
     Item,
     Link(String, Option<String>),
+    /// Spartan input prompt (`=: url label`), asking for data to send to the url
+    Prompt(String, Option<String>),
 }
 
 #[derive(Debug, Clone)]
@@ -24,6 +28,11 @@ pub enum Event<'a> {
     BlankLine,
 }
 
+/// Parsers turning a document, one line at a time, into [`Event`]s
+pub trait LineParser {
+    fn parse_line<'a>(&mut self, line: &'a str, res: &mut Vec<Event<'a>>);
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Parser {
     tag_stack: Vec<Tag>,
@@ -92,6 +101,14 @@ impl Parser {
                 label.map(|x| x.to_string()),
             )));
             res.push(Event::End);
+        } else if let Some(captures) = R_SPARTAN_PROMPT.captures(line.trim_end()) {
+            let href = captures.name("href").unwrap();
+            let label = captures.name("label").map(|x| x.as_str());
+            res.push(Event::Start(Tag::Prompt(
+                href.as_str().to_string(),
+                label.map(|x| x.to_string()),
+            )));
+            res.push(Event::End);
         } else {
             res.push(Event::Start(Tag::Paragraph));
             res.push(Event::Text(line.trim_end()));
@@ -99,3 +116,9 @@ impl Parser {
         }
     }
 }
+
+impl LineParser for Parser {
+    fn parse_line<'a>(&mut self, line: &'a str, res: &mut Vec<Event<'a>>) {
+        Parser::parse_line(self, line, res)
+    }
+}
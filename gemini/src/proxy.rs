@@ -0,0 +1,84 @@
+use std::str::FromStr;
+
+use url::Url;
+
+const DEFAULT_PORT: u16 = 1080;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// Host names are resolved locally, the proxy receives the resolved address
+    Socks5,
+    /// Host names are sent to the proxy, which resolves them. Needed for onion services
+    Socks5h,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid proxy {0:?}, expected socks5://host:port or socks5h://host:port")]
+pub struct InvalidProxy(String);
+
+/// SOCKS5 proxy used for every outgoing connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Proxy {
+    /// Proxy uri understood by gio. Gio always sends the host names to SOCKS5 proxies,
+    /// the local resolution of [`ProxyKind::Socks5`] is done before connecting
+    pub(crate) fn gio_uri(&self) -> String {
+        format!("socks5://{}:{}", self.host, self.port)
+    }
+    /// Onion services can only be resolved by the proxy, whatever the kind
+    pub(crate) fn resolves_locally(&self, host: &str) -> bool {
+        self.kind == ProxyKind::Socks5 && !host.ends_with(".onion")
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = InvalidProxy;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidProxy(s.to_owned());
+        let url = Url::parse(s).map_err(|_| invalid())?;
+        let kind = match url.scheme() {
+            "socks5" => ProxyKind::Socks5,
+            "socks5h" => ProxyKind::Socks5h,
+            _ => return Err(invalid()),
+        };
+        let host = url.host_str().ok_or_else(invalid)?.to_owned();
+        Ok(Self {
+            kind,
+            host,
+            port: url.port().unwrap_or(DEFAULT_PORT),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<(), InvalidProxy> {
+        let proxy: Proxy = "socks5h://127.0.0.1:9050".parse()?;
+        assert_eq!(
+            proxy,
+            Proxy {
+                kind: ProxyKind::Socks5h,
+                host: "127.0.0.1".into(),
+                port: 9050
+            }
+        );
+        assert_eq!(proxy.gio_uri(), "socks5://127.0.0.1:9050");
+
+        let proxy: Proxy = "socks5://localhost".parse()?;
+        assert_eq!(proxy.port, 1080);
+        assert!(proxy.resolves_locally("example.org"));
+        assert!(!proxy.resolves_locally("example.onion"));
+
+        assert!("http://localhost:8080".parse::<Proxy>().is_err());
+        assert!("localhost:9050".parse::<Proxy>().is_err());
+        Ok(())
+    }
+}
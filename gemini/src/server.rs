@@ -0,0 +1,417 @@
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+use futures::io::BufReader;
+use futures::prelude::*;
+use gio::prelude::*;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::client::response_header;
+use crate::Status;
+
+// The request is an absolute url of at most 1024 bytes, followed by <CR><LF>
+const MAX_REQUEST_LEN: u64 = 1024 + 2;
+const INDEX_FILE: &str = "index.gmi";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("Gio error: {0}")]
+    Gio(#[from] glib::Error),
+    #[error("Failed to generate the certificate: {0}")]
+    Certificate(#[from] rcgen::Error),
+}
+
+/// Request received by a [`Server`]
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub url: Url,
+    /// Client certificate, if the client presented one
+    pub certificate: Option<gio::TlsCertificate>,
+}
+
+/// Response sent by a [`Server`]. The body is only sent with the success statuses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub status: Status,
+    pub meta: String,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn new(status: Status, meta: &str) -> Self {
+        Self {
+            status,
+            meta: meta.to_owned(),
+            body: vec![],
+        }
+    }
+    pub fn success(mime: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status: Status::Success(20),
+            meta: mime.to_owned(),
+            body: body.into(),
+        }
+    }
+    pub fn not_found() -> Self {
+        Self::new(Status::PermFail(51), "Not found")
+    }
+    fn into_bytes(self) -> Vec<u8> {
+        let Some(mut bytes) = response_header(self.status, &self.meta) else {
+            return Self::new(Status::TempFail(42), "Response meta too long").into_bytes();
+        };
+        if let Status::Success(_) = self.status {
+            bytes.extend(self.body);
+        }
+        bytes
+    }
+}
+
+type Handler = Rc<dyn Fn(Request) -> LocalBoxFuture<'static, Reply>>;
+
+/// Self-signed certificate (with its private key) valid for `host`
+pub fn generate_certificate(host: &str) -> Result<gio::TlsCertificate, ServerError> {
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec![host.to_owned()])?;
+    let pem = cert.pem() + &key_pair.serialize_pem();
+    Ok(gio::TlsCertificate::from_pem(&pem)?)
+}
+
+/// Minimal gemini server, serving a directory or the replies of a handler.
+///
+/// The server isn't a proxy: the requests for another scheme, or for a host its certificate
+/// isn't valid for, are refused with `53 Proxy request refused`.
+///
+/// ```no_run
+/// # use gemini::server::{Reply, Server};
+/// let server = Server::handler(|req| async move {
+///     Reply::success("text/gemini", format!("# You requested {}", req.url))
+/// })
+/// .listen(1965)
+/// .unwrap();
+/// ```
+pub struct Server {
+    handler: Handler,
+    certificate: Option<gio::TlsCertificate>,
+}
+
+impl std::fmt::Debug for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Server")
+            .field("certificate", &self.certificate.is_some())
+            .finish()
+    }
+}
+
+impl Server {
+    /// Replies to each request with the result of `handler`
+    pub fn handler<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + 'static,
+        Fut: Future<Output = Reply> + 'static,
+    {
+        Self {
+            handler: Rc::new(move |req| handler(req).boxed_local()),
+            certificate: None,
+        }
+    }
+    /// Serves the files under `root`. The directories are served using their `index.gmi` file
+    pub fn directory(root: impl Into<PathBuf>) -> Self {
+        let root = Rc::new(root.into());
+        Self::handler(move |req| {
+            let root = root.clone();
+            async move { serve_file(&root, &req.url).await }
+        })
+    }
+    /// Certificate presented to the clients. By default, a certificate for `localhost` is
+    /// generated when the server starts listening
+    pub fn certificate(mut self, certificate: gio::TlsCertificate) -> Self {
+        self.certificate = Some(certificate);
+        self
+    }
+    /// Accepts the connections on `port`, or on a free port if it's 0. The connections are
+    /// served on the thread default main context, until the returned handle is dropped
+    pub fn listen(self, port: u16) -> Result<ServerHandle, ServerError> {
+        let certificate = match self.certificate {
+            Some(certificate) => certificate,
+            None => generate_certificate("localhost")?,
+        };
+        let listener = gio::SocketListener::new();
+        let port = if port == 0 {
+            listener.add_any_inet_port(None::<&glib::Object>)?
+        } else {
+            listener.add_inet_port(port, None::<&glib::Object>)?;
+            port
+        };
+
+        let handler = self.handler;
+        let ctx = glib::MainContext::ref_thread_default();
+        let accept_listener = listener.clone();
+        ctx.spawn_local(async move {
+            loop {
+                let connection = match accept_listener.accept_future().await {
+                    Ok((connection, _)) => connection,
+                    Err(e) if e.matches(gio::IOErrorEnum::Closed) => break,
+                    Err(e) => {
+                        log::error!("Failed to accept a connection: {}", e);
+                        break;
+                    }
+                };
+                let handler = handler.clone();
+                let certificate = certificate.clone();
+                glib::MainContext::ref_thread_default().spawn_local(async move {
+                    if let Err(e) = serve_connection(connection, &certificate, handler).await {
+                        log::warn!("Failed to serve a connection: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(ServerHandle { port, listener })
+    }
+}
+
+/// Running [`Server`]. Dropping it stops accepting new connections
+#[derive(Debug)]
+pub struct ServerHandle {
+    port: u16,
+    listener: gio::SocketListener,
+}
+
+impl ServerHandle {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.listener.close();
+    }
+}
+
+async fn serve_connection(
+    connection: gio::SocketConnection,
+    certificate: &gio::TlsCertificate,
+    handler: Handler,
+) -> Result<(), ServerError> {
+    let tls = gio::TlsServerConnection::new(&connection, Some(certificate))?;
+    // Client certificates are self-signed, any of them is an acceptable identity
+    tls.set_authentication_mode(gio::TlsAuthenticationMode::Requested);
+    tls.connect_accept_certificate(|_, _, _| true);
+    tls.handshake_future(glib::Priority::default()).await?;
+
+    let peer_certificate = tls.peer_certificate();
+    let stream = tls.into_async_read_write().map_err(|_| {
+        glib::Error::new(
+            gio::IOErrorEnum::NotSupported,
+            "The connection streams aren't pollable",
+        )
+    })?;
+    let mut stream = BufReader::new(stream);
+
+    let mut line = String::new();
+    let read = (&mut stream)
+        .take(MAX_REQUEST_LEN)
+        .read_line(&mut line)
+        .await;
+    let url = match read {
+        Ok(_) if line.ends_with("\r\n") => Url::parse(line.trim_end()).ok(),
+        _ => None,
+    };
+    let reply = match url {
+        Some(url) if !is_served(certificate, &url) => {
+            Reply::new(Status::PermFail(53), "Proxy request refused")
+        }
+        Some(url) => {
+            handler(Request {
+                url,
+                certificate: peer_certificate,
+            })
+            .await
+        }
+        None => Reply::new(Status::PermFail(59), "Bad request"),
+    };
+
+    // The client closing the connection early isn't an error of the server
+    if stream.write_all(&reply.into_bytes()).await.is_ok() {
+        stream.close().await.ok();
+    }
+    Ok(())
+}
+
+/// Whether `url` is a gemini url for a host the server `certificate` is valid for
+fn is_served(certificate: &gio::TlsCertificate, url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let identity = gio::NetworkAddress::new(host, url.port().unwrap_or(1965));
+    url.scheme() == "gemini"
+        && !certificate
+            .verify(Some(&identity), None::<&gio::TlsCertificate>)
+            .contains(gio::TlsCertificateFlags::BAD_IDENTITY)
+}
+
+/// Path of the file requested by `url`, refusing the paths leaving `root`
+fn file_path(root: &Path, url: &Url) -> Option<PathBuf> {
+    let mut path = root.to_owned();
+    for segment in url.path().split('/') {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        match &*segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains('/') => return None,
+            segment => path.push(segment),
+        }
+    }
+    Some(path)
+}
+
+fn mime_type(path: &Path, data: &[u8]) -> String {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gmi") | Some("gemini") => "text/gemini".to_owned(),
+        _ => {
+            let (content_type, _) = gio::content_type_guess(Some(path), data);
+            gio::content_type_get_mime_type(&content_type)
+                .map(|mime| mime.to_string())
+                .unwrap_or_else(|| "application/octet-stream".to_owned())
+        }
+    }
+}
+
+/// Canonical form of `path`, if it exists and is under `root`. The symlinks leading out of
+/// `root` are refused
+fn resolve(root: &Path, path: &Path) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+    let path = path.canonicalize().ok()?;
+    path.starts_with(&root).then_some(path)
+}
+
+async fn serve_file(root: &Path, url: &Url) -> Reply {
+    let Some(path) = file_path(root, url) else {
+        return Reply::new(Status::PermFail(59), "Bad request");
+    };
+    let Some(mut path) = resolve(root, &path) else {
+        return Reply::not_found();
+    };
+    if path.is_dir() {
+        if !url.path().ends_with('/') {
+            let mut url = url.clone();
+            url.set_path(&format!("{}/", url.path()));
+            return Reply::new(Status::Redirect(31), url.as_str());
+        }
+        let Some(index) = resolve(root, &path.join(INDEX_FILE)) else {
+            return Reply::not_found();
+        };
+        path = index;
+    }
+    match gio::File::for_path(&path).load_contents_future().await {
+        Ok((data, _)) => Reply::success(&mime_type(&path, &data), data.to_vec()),
+        Err(_) => Reply::not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientBuilder;
+
+    fn block_on<T>(f: impl Future<Output = T>) -> T {
+        glib::MainContext::new().block_on(f)
+    }
+
+    #[test]
+    fn paths() {
+        let root = Path::new("/srv/capsule");
+        let path = |url| file_path(root, &Url::parse(url).unwrap());
+        assert_eq!(
+            path("gemini://localhost/docs/read%20me.gmi"),
+            Some(root.join("docs/read me.gmi"))
+        );
+        assert_eq!(path("gemini://localhost"), Some(root.to_owned()));
+        // The dot segments are resolved while parsing the url
+        assert_eq!(
+            path("gemini://localhost/docs/%2E%2E/%2E%2E/etc"),
+            Some(root.join("etc"))
+        );
+        assert_eq!(path("gemini://localhost/a%2Fb"), None);
+    }
+
+    #[test]
+    fn reply_framing() {
+        let reply = Reply::success("text/gemini", "# Hi");
+        assert_eq!(reply.into_bytes(), b"20 text/gemini\r\n# Hi");
+        let reply = Reply {
+            body: b"ignored".to_vec(),
+            ..Reply::not_found()
+        };
+        assert_eq!(reply.into_bytes(), b"51 Not found\r\n");
+    }
+
+    #[test]
+    fn serve_handler() -> Result<(), crate::Error> {
+        block_on(async {
+            let server = Server::handler(|req| async move {
+                Reply::success("text/gemini", format!("# {}", req.url.path()))
+            })
+            .listen(0)
+            .unwrap();
+
+            let client = ClientBuilder::new().build();
+            let url = format!("gemini://localhost:{}/hello", server.port());
+            let res = client.fetch(&url).await?;
+            assert_eq!(res.status(), Status::Success(20));
+            assert_eq!(res.meta(), "text/gemini");
+
+            let mut body = String::new();
+            res.body().unwrap().read_to_string(&mut body).await?;
+            assert_eq!(body, "# /hello");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn serve_directory() -> Result<(), crate::Error> {
+        let dir = std::env::temp_dir().join(format!("gemini-capsule-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("docs"))?;
+        std::fs::write(dir.join("docs").join(INDEX_FILE), "# Docs\n")?;
+
+        let res = block_on(async {
+            let server = Server::directory(&dir).listen(0).unwrap();
+            let client = ClientBuilder::new().redirect(true).build();
+            let base = format!("gemini://localhost:{}", server.port());
+
+            let res = client.fetch(&format!("{}/docs", base)).await?;
+            assert_eq!(res.status(), Status::Success(20));
+            assert_eq!(res.meta(), "text/gemini");
+            assert_eq!(res.redirects().len(), 1);
+
+            let res = client.fetch(&format!("{}/missing.gmi", base)).await?;
+            assert_eq!(res.status(), Status::PermFail(51));
+
+            // The symlinks can't lead out of the root
+            #[cfg(unix)]
+            {
+                std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("tmp"))?;
+                let res = client.fetch(&format!("{}/tmp/", base)).await?;
+                assert_eq!(res.status(), Status::PermFail(51));
+            }
+
+            // Neither other hosts nor other schemes are served
+            let proxy = format!("localhost:{}", server.port());
+            let client = ClientBuilder::new()
+                .scheme_proxy("gemini", &proxy)
+                .scheme_proxy("http", &proxy)
+                .build();
+            for url in ["gemini://example.org/docs/", "http://localhost/docs/"] {
+                let res = client.fetch(url).await?;
+                assert_eq!(res.status(), Status::PermFail(53));
+            }
+            Ok(())
+        });
+
+        std::fs::remove_dir_all(&dir)?;
+        res
+    }
+}
@@ -0,0 +1,124 @@
+use futures::io::Cursor;
+use futures::prelude::*;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::client::send_request;
+use crate::{Client, Error, InvalidStatus, ProtoError, Response, Status};
+
+const DEFAULT_PORT: u16 = 300;
+
+/// Builds the request line (`host path content-length`) followed by the data.
+/// The data to upload is taken from the url query
+fn request(url: &Url) -> Result<Vec<u8>, Error> {
+    let host = url.host_str().ok_or(Error::InvalidHost)?;
+    let path = match url.path() {
+        "" => "/",
+        path => path,
+    };
+    let data: Vec<u8> = url
+        .query()
+        .map(|query| percent_decode_str(query).collect())
+        .unwrap_or_default();
+
+    let mut request = format!("{} {} {}\r\n", host, path, data.len()).into_bytes();
+    request.extend(data);
+    Ok(request)
+}
+
+/// Parses a spartan response, mapping its single digit status to the equivalent gemini one
+async fn read_response(
+    mut async_readable: impl AsyncRead + std::marker::Unpin + 'static,
+) -> Result<Response, Error> {
+    let mut buffer = Vec::with_capacity(1024);
+    // 2 bytes for the status, 1024 max bytes for the meta
+    (&mut async_readable)
+        .take(2 + 1024)
+        .read_to_end(&mut buffer)
+        .await?;
+
+    let meta_end = buffer
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .ok_or(Error::InvalidProtocolData(ProtoError::MetaNotFound))?;
+
+    if !matches!(buffer.get(1), Some(b' ') | Some(b'\r')) {
+        return Err(Error::InvalidProtocolData(InvalidStatus.into()));
+    }
+    let status = match buffer.first() {
+        Some(b'2') => Status::Success(20),
+        Some(b'3') => Status::Redirect(30),
+        Some(b'4') => Status::PermFail(59),
+        Some(b'5') => Status::TempFail(40),
+        _ => return Err(Error::InvalidProtocolData(InvalidStatus.into())),
+    };
+    let meta = String::from_utf8_lossy(buffer.get(2..meta_end).unwrap_or(&[])).to_string();
+
+    let cursor = Cursor::new(buffer.split_off(meta_end + 2));
+    Ok(Response::new(
+        status,
+        meta,
+        Box::new(cursor.chain(async_readable)),
+    ))
+}
+
+impl Client {
+    /// Fetches a spartan url. Redirects are followed like in [`Client::fetch`]
+    pub async fn fetch_spartan(&self, url_str: &str) -> Result<Response, Error> {
+        let mut url = Url::parse(url_str)?;
+        let mut redirects = vec![];
+        loop {
+            let res = self.fetch_spartan_internal(&url).await?;
+            match self.next_hop(&url, &res, &mut redirects)? {
+                Some(target) => url = target,
+                None => return Ok(res.with_redirects(redirects)),
+            }
+        }
+    }
+    async fn fetch_spartan_internal(&self, url: &Url) -> Result<Response, Error> {
+        if url.scheme() != "spartan" {
+            return Err(Error::SchemeNotSupported);
+        }
+        let request = request(url)?;
+        let connection = self.connect_plain(url, DEFAULT_PORT).await?;
+        let async_readable = send_request(connection, request).await?;
+        read_response(async_readable).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response_from_bytes(bytes: &[u8]) -> Result<Response, Error> {
+        let async_read = futures::io::Cursor::new(bytes.to_vec());
+        glib::MainContext::new().block_on(read_response(async_read))
+    }
+
+    #[test]
+    fn request_line() -> Result<(), Error> {
+        let url = Url::parse("spartan://example.org")?;
+        assert_eq!(request(&url)?, b"example.org / 0\r\n");
+
+        let url = Url::parse("spartan://example.org/guestbook?hello%20world")?;
+        assert_eq!(request(&url)?, b"example.org /guestbook 11\r\nhello world");
+        Ok(())
+    }
+
+    #[test]
+    fn statuses() -> Result<(), Error> {
+        let res = response_from_bytes(b"2 text/gemini\r\n# Hello")?;
+        assert_eq!(res.status(), Status::Success(20));
+        assert_eq!(res.meta(), "text/gemini");
+
+        let res = response_from_bytes(b"3 /new-path\r\n")?;
+        assert_eq!(res.status(), Status::Redirect(30));
+        assert_eq!(res.meta(), "/new-path");
+
+        let res = response_from_bytes(b"4 Not found\r\n")?;
+        assert_eq!(res.status(), Status::PermFail(59));
+
+        assert!(response_from_bytes(b"20 text/gemini\r\n").is_err());
+        Ok(())
+    }
+}
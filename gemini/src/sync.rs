@@ -0,0 +1,548 @@
+//! `Send + Sync` gemini client, usable from any async runtime.
+//!
+//! Unlike [`crate::Client`], which runs on the GLib main loop, this client opens its
+//! connections with `async-net` and `rustls`. It only supports gemini urls and is enabled by the
+//! `rustls` feature.
+//!
+//! ```no_run
+//! # async fn crawl() -> Result<(), gemini::Error> {
+//! let client = gemini::sync::ClientBuilder::new().redirect(true).build();
+//! let res = client.fetch("gemini://geminiprotocol.net/").await?;
+//! println!("{:?} {}", res.status(), res.meta());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_net::TcpStream;
+use futures::future::{self, Either};
+use futures::io::Cursor;
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use futures_rustls::rustls;
+use futures_rustls::TlsConnector;
+use log::debug;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use url::Url;
+
+use crate::client::{read_header, ClientOptions, RedirectHop, RedirectPolicy};
+use crate::identity::Identity;
+use crate::known_hosts;
+use crate::mime::{self, Mime};
+use crate::{CertificateError, Error, Status};
+
+// Timeout measured in seconds
+const MAX_TIMEOUT_SECONDS: u64 = 10;
+
+/// Validates the DER encoded certificate presented by the server at `host:port`
+pub trait Validator: Send {
+    fn validate(&mut self, host: &str, port: u16, cert: &[u8]) -> Result<(), CertificateError>;
+}
+
+impl<F: FnMut(&str, u16, &[u8]) -> Result<(), CertificateError> + Send> Validator for F {
+    fn validate(&mut self, host: &str, port: u16, cert: &[u8]) -> Result<(), CertificateError> {
+        self(host, port, cert)
+    }
+}
+
+/// Chooses the client certificate to present when connecting to `url`
+pub trait IdentityProvider: Send {
+    fn identity_for(&mut self, url: &Url) -> Option<Identity>;
+}
+
+impl<F: FnMut(&Url) -> Option<Identity> + Send> IdentityProvider for F {
+    fn identity_for(&mut self, url: &Url) -> Option<Identity> {
+        self(url)
+    }
+}
+
+pub struct Response {
+    status: Status,
+    meta: String,
+    body: Box<dyn AsyncRead + Send + Unpin>,
+    redirects: Vec<RedirectHop>,
+}
+
+impl std::fmt::Debug for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("meta", &self.meta)
+            .field("redirects", &self.redirects)
+            .finish()
+    }
+}
+
+impl Response {
+    pub fn status(&self) -> Status {
+        self.status
+    }
+    pub fn meta(&self) -> &str {
+        &self.meta
+    }
+    pub fn meta_owned(self) -> String {
+        self.meta
+    }
+    /// Parsed mime type, if the response is a success
+    pub fn mime(&self) -> Option<Mime> {
+        mime::from_meta(self.status, &self.meta)
+    }
+    /// Redirects followed before getting this response, in order
+    pub fn redirects(&self) -> &[RedirectHop] {
+        &self.redirects
+    }
+    pub fn body(self) -> Option<impl AsyncRead + Send + Unpin> {
+        match self.status {
+            Status::Success(_) => Some(self.body),
+            _ => None,
+        }
+    }
+    pub async fn from_async_read(
+        mut async_readable: impl AsyncRead + Send + Unpin + 'static,
+    ) -> Result<Self, Error> {
+        let (status, meta, rest) = read_header(&mut async_readable).await?;
+        Ok(Self {
+            status,
+            meta,
+            body: Box::new(Cursor::new(rest).chain(async_readable)),
+            redirects: vec![],
+        })
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct ClientBuilder {
+    options: ClientOptions,
+    validator: Option<Arc<Mutex<dyn Validator>>>,
+    identity_provider: Option<Arc<Mutex<dyn IdentityProvider>>>,
+}
+
+impl std::fmt::Debug for ClientBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientBuilder")
+            .field("options", &self.options)
+            .field("validator", &self.validator.is_some())
+            .field("identity_provider", &self.identity_provider.is_some())
+            .finish()
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    pub fn redirect(mut self, redirect: bool) -> Self {
+        self.options.redirect = redirect;
+        self
+    }
+    /// Maximum number of redirects followed by a single request
+    pub fn max_redirects(mut self, max: u8) -> Self {
+        self.options.max_redirects = max;
+        self
+    }
+    /// Policy for the redirects to another host (or port)
+    pub fn cross_host_redirects(mut self, policy: RedirectPolicy) -> Self {
+        self.options.cross_host = policy;
+        self
+    }
+    /// Policy for the redirects to another scheme. This client only follows gemini urls
+    pub fn cross_scheme_redirects(mut self, policy: RedirectPolicy) -> Self {
+        self.options.cross_scheme = policy;
+        self
+    }
+    pub fn validator(mut self, f: impl Validator + 'static) -> Self {
+        self.validator = Some(Arc::new(Mutex::new(f)));
+        self
+    }
+    pub fn identity_provider(mut self, f: impl IdentityProvider + 'static) -> Self {
+        self.identity_provider = Some(Arc::new(Mutex::new(f)));
+        self
+    }
+    pub fn build(self) -> Client {
+        Client {
+            options: self.options,
+            validator: self.validator.unwrap_or_else(Client::default_validator),
+            identity_provider: self.identity_provider,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Client {
+    options: ClientOptions,
+    validator: Arc<Mutex<dyn Validator>>,
+    identity_provider: Option<Arc<Mutex<dyn IdentityProvider>>>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        ClientBuilder::new().build()
+    }
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client")
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl Client {
+    /// Trusts the first certificate seen for each host, for the lifetime of the client
+    pub fn default_validator() -> Arc<Mutex<dyn Validator>> {
+        let mut known_hosts = known_hosts::KnownHostsMap::new();
+        Arc::new(Mutex::new(move |host: &str, port: u16, cert: &[u8]| {
+            known_hosts::validate_der(&mut known_hosts, host, port, cert)
+        }))
+    }
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn fetch(&self, url_str: &str) -> Result<Response, Error> {
+        let mut url = Url::parse(url_str)?;
+        let mut redirects = vec![];
+        loop {
+            let res = self.fetch_internal(&url).await?;
+            match self
+                .options
+                .next_hop(&url, res.status, &res.meta, &mut redirects)?
+            {
+                Some(target) => url = target,
+                None => return Ok(Response { redirects, ..res }),
+            }
+        }
+    }
+    fn tls_config(
+        &self,
+        url: &Url,
+        verifier: Arc<TofuVerifier>,
+    ) -> Result<rustls::ClientConfig, Error> {
+        let identity = self
+            .identity_provider
+            .as_ref()
+            .and_then(|provider| provider.lock().ok()?.identity_for(url));
+
+        let builder = rustls::ClientConfig::builder_with_provider(verifier.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+        match identity {
+            Some(identity) => {
+                let pem = identity.pem().as_bytes();
+                let certs = CertificateDer::pem_slice_iter(pem)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(tls_error)?;
+                let key = PrivateKeyDer::from_pem_slice(pem).map_err(tls_error)?;
+                builder.with_client_auth_cert(certs, key).map_err(tls_error)
+            }
+            None => Ok(builder.with_no_client_auth()),
+        }
+    }
+    async fn fetch_internal(&self, url: &Url) -> Result<Response, Error> {
+        if url.scheme() != "gemini" {
+            return Err(Error::SchemeNotSupported);
+        }
+        let host = match url.host() {
+            Some(url::Host::Domain(domain)) => domain.to_owned(),
+            Some(url::Host::Ipv4(ip)) => ip.to_string(),
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),
+            None => return Err(Error::InvalidHost),
+        };
+        let port = url.port().unwrap_or(1965);
+        let server_name = ServerName::try_from(host.clone()).map_err(|_| Error::InvalidHost)?;
+
+        let verifier = Arc::new(TofuVerifier {
+            host: host.clone(),
+            port,
+            validator: self.validator.clone(),
+            provider: Arc::new(ring::default_provider()),
+            error: Mutex::new(None),
+        });
+        let config = self.tls_config(url, verifier.clone())?;
+
+        let timeout = async_io::Timer::after(Duration::from_secs(MAX_TIMEOUT_SECONDS));
+        let connect = Box::pin(TcpStream::connect((host.as_str(), port)));
+        let stream = match future::select(connect, timeout).await {
+            Either::Left((stream, _)) => stream?,
+            Either::Right(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        };
+        // A server stalling in the handshake or the response mustn't hang the request forever
+        let stream = TimeoutStream::new(stream, Duration::from_secs(MAX_TIMEOUT_SECONDS));
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| match verifier.error.lock().ok().and_then(|e| *e) {
+                Some(error) => Error::Tls {
+                    host: host.clone(),
+                    port,
+                    error,
+                },
+                None => Error::Io(e),
+            })?;
+
+        stream
+            .write_all((url.to_string() + "\r\n").as_bytes())
+            .await?;
+        stream.flush().await?;
+        debug!("Request sent at {}", url);
+
+        Response::from_async_read(RustlsConnection(stream)).await
+    }
+}
+
+fn tls_error(e: impl std::fmt::Display) -> Error {
+    Error::Io(io::Error::other(e.to_string()))
+}
+
+/// Delegates the certificate validation to the [`Validator`] of the client, remembering its
+/// error to return it instead of the handshake failure
+struct TofuVerifier {
+    host: String,
+    port: u16,
+    validator: Arc<Mutex<dyn Validator>>,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+    error: Mutex<Option<CertificateError>>,
+}
+
+impl std::fmt::Debug for TofuVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TofuVerifier")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl TofuVerifier {
+    fn algorithms(&self) -> &WebPkiSupportedAlgorithms {
+        &self.provider.signature_verification_algorithms
+    }
+}
+
+impl ServerCertVerifier for TofuVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let res = match self.validator.lock() {
+            Ok(mut validator) => validator.validate(&self.host, self.port, end_entity),
+            Err(_) => Err(CertificateError::GenericError),
+        };
+        match res {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(e) => {
+                if let Ok(mut error) = self.error.lock() {
+                    *error = Some(e);
+                }
+                Err(rustls::Error::InvalidCertificate(
+                    rustls::CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+        }
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, self.algorithms())
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, self.algorithms())
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms().supported_schemes()
+    }
+}
+
+/// Stream failing with [`io::ErrorKind::TimedOut`] when a read or a write makes no progress
+/// for `timeout`
+struct TimeoutStream<S> {
+    inner: S,
+    timeout: Duration,
+    read_timer: Option<async_io::Timer>,
+    write_timer: Option<async_io::Timer>,
+}
+
+impl<S> TimeoutStream<S> {
+    fn new(inner: S, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            read_timer: None,
+            write_timer: None,
+        }
+    }
+}
+
+/// Returns `poll` once ready, or a timeout error if `timer` fires first. The timer is started
+/// when the operation starts waiting
+fn poll_timeout<T>(
+    timer: &mut Option<async_io::Timer>,
+    timeout: Duration,
+    cx: &mut Context<'_>,
+    poll: Poll<io::Result<T>>,
+) -> Poll<io::Result<T>> {
+    if poll.is_ready() {
+        *timer = None;
+        return poll;
+    }
+    let fired = Pin::new(timer.get_or_insert_with(|| async_io::Timer::after(timeout)))
+        .poll(cx)
+        .is_ready();
+    if fired {
+        *timer = None;
+        return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+    }
+    Poll::Pending
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        poll_timeout(&mut this.read_timer, this.timeout, cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        poll_timeout(&mut this.write_timer, this.timeout, cx, poll)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        poll_timeout(&mut this.write_timer, this.timeout, cx, poll)
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+type TlsStream = futures_rustls::client::TlsStream<TimeoutStream<TcpStream>>;
+
+/// Many servers close the connection without sending the tls close_notify alert: treat it as
+/// the end of the body, like the gio transport does
+struct RustlsConnection(TlsStream);
+
+impl AsyncRead for RustlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.0)
+            .poll_read(cx, buf)
+            .map(|res| match res {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    debug!("suppressed rustls unexpected eof error");
+                    Ok(0)
+                }
+                res => res,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Reply, Server};
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    #[test]
+    fn client_is_send_sync() {
+        let client = ClientBuilder::new().redirect(true).build();
+        assert_send_sync(&client);
+        let fetch = client.fetch("gemini://example.org/");
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&fetch);
+    }
+
+    #[test]
+    fn stalled_stream_times_out() -> Result<(), Error> {
+        glib::MainContext::new().block_on(async {
+            // The connection is accepted by the backlog, but nothing is ever sent
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            let stream = TcpStream::connect(listener.local_addr()?).await?;
+            let mut stream = TimeoutStream::new(stream, Duration::from_millis(50));
+            let mut buf = [0; 16];
+            let e = stream.read(&mut buf).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn fetch_from_server() -> Result<(), Error> {
+        glib::MainContext::new().block_on(async {
+            let server = Server::handler(|req| async move {
+                match req.url.path() {
+                    "/old" => Reply::new(Status::Redirect(31), "/new"),
+                    path => Reply::success("text/gemini", format!("# {}", path)),
+                }
+            })
+            .listen(0)
+            .unwrap();
+
+            let client = ClientBuilder::new().redirect(true).build();
+            let url = format!("gemini://localhost:{}/old", server.port());
+            let res = client.fetch(&url).await?;
+            assert_eq!(res.status(), Status::Success(20));
+            assert_eq!(res.redirects().len(), 1);
+
+            let mut body = String::new();
+            res.body().unwrap().read_to_string(&mut body).await?;
+            assert_eq!(body, "# /new");
+
+            // The errors of the validator are returned as is
+            let port = server.port();
+            let client = ClientBuilder::new()
+                .validator(move |host: &str, p: u16, _: &[u8]| {
+                    assert_eq!((host, p), ("localhost", port));
+                    Err(CertificateError::BadIdentity)
+                })
+                .build();
+            assert!(matches!(
+                client.fetch(&url).await,
+                Err(Error::Tls {
+                    error: CertificateError::BadIdentity,
+                    port: p,
+                    ..
+                }) if p == port
+            ));
+            Ok(())
+        })
+    }
+}
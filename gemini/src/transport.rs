@@ -0,0 +1,430 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use futures::channel::mpsc;
+use futures::future::LocalBoxFuture;
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use gio::prelude::*;
+use log::debug;
+
+use crate::proxy::Proxy;
+use crate::{Error, Validator};
+
+// Timeout measured in seconds
+const MAX_TIMEOUT_SECONDS: u32 = 10;
+
+/// Bidirectional byte stream opened by a [`Transport`]
+pub trait Connection: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Connection for T {}
+
+/// Tls parameters of an [`Endpoint`]
+#[derive(Clone)]
+pub struct Tls {
+    /// Validates the certificate presented by the server. Its errors must be returned as
+    /// [`Error::Tls`]
+    pub validator: Rc<RefCell<dyn Validator>>,
+    /// Client certificate to present to the server
+    pub identity: Option<gio::TlsCertificate>,
+    /// Filled by the transport with the certificate presented by the server, once the
+    /// handshake is done
+    pub peer_certificate: Rc<RefCell<Option<gio::TlsCertificate>>>,
+}
+
+/// Server to connect to
+#[derive(Clone)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    /// `None` for the protocols working over plain tcp
+    pub tls: Option<Tls>,
+}
+
+impl std::fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Endpoint")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+/// Opens the connections of a [`crate::Client`]
+pub trait Transport {
+    fn connect(&self, endpoint: Endpoint)
+        -> LocalBoxFuture<'_, Result<Box<dyn Connection>, Error>>;
+}
+
+/// Default transport, opening tcp connections with gio, optionally through a SOCKS5 proxy
+#[derive(Debug, Clone, Default)]
+pub struct TcpTransport {
+    proxy: Option<Proxy>,
+}
+
+impl TcpTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_proxy(proxy: Proxy) -> Self {
+        Self { proxy: Some(proxy) }
+    }
+    fn socket_client(&self) -> gio::SocketClient {
+        let socket = gio::SocketClient::new();
+        socket.set_timeout(MAX_TIMEOUT_SECONDS);
+        if let Some(proxy) = &self.proxy {
+            let resolver =
+                gio::SimpleProxyResolver::new(Some(&proxy.gio_uri()), Vec::<&str>::new());
+            socket.set_proxy_resolver(Some(&resolver));
+        }
+        socket
+    }
+    /// Address to hand to the socket client. When the proxy doesn't resolve host names,
+    /// the name is resolved here and the proxy only receives the ip address
+    async fn connectable(
+        &self,
+        addr: &gio::NetworkAddress,
+    ) -> Result<gio::SocketConnectable, Error> {
+        match &self.proxy {
+            Some(proxy) if proxy.resolves_locally(&addr.hostname()) => {
+                let ips = gio::Resolver::default()
+                    .lookup_by_name_future(&addr.hostname())
+                    .await
+                    .map_err(|e| Error::Gio(e.to_string()))?;
+                let ip = ips.first().ok_or(Error::InvalidHost)?;
+                Ok(gio::InetSocketAddress::new(ip, addr.port()).upcast())
+            }
+            _ => Ok(addr.clone().upcast()),
+        }
+    }
+    async fn connect_tcp(&self, endpoint: Endpoint) -> Result<Box<dyn Connection>, Error> {
+        let addr = gio::NetworkAddress::new(&endpoint.host, endpoint.port);
+        let connectable = self.connectable(&addr).await?;
+        let socket = self.socket_client();
+
+        let tls_error = Rc::new(RefCell::new(None));
+        if let Some(Tls {
+            validator,
+            identity,
+            peer_certificate,
+        }) = endpoint.tls
+        {
+            socket.set_tls(true);
+
+            let host = endpoint.host.clone();
+            let port = endpoint.port;
+            let tls_error_clone = tls_error.clone();
+            // gio only asks to accept the certificates it finds invalid
+            let validated = Rc::new(Cell::new(false));
+            socket.connect_event(move |_this, event, _connectable, connection| {
+                use gio::SocketClientEvent;
+                if event == SocketClientEvent::TlsHandshaked {
+                    let connection = connection
+                        .as_ref()
+                        .unwrap()
+                        .dynamic_cast_ref::<gio::TlsClientConnection>()
+                        .unwrap();
+                    let cert = connection.peer_certificate();
+                    if let (false, Some(cert)) = (validated.get(), &cert) {
+                        let errors = connection.peer_certificate_errors();
+                        if let Err(e) = validator.borrow_mut().validate(&host, port, cert, errors) {
+                            tls_error_clone.replace(Some(e));
+                        }
+                    }
+                    peer_certificate.replace(cert);
+                }
+                if event == SocketClientEvent::TlsHandshaking {
+                    let connection = connection
+                        .as_ref()
+                        .unwrap()
+                        .dynamic_cast_ref::<gio::TlsClientConnection>()
+                        .unwrap();
+
+                    // The connectable may be a resolved address, the certificate is still
+                    // expected to match the host name
+                    connection.set_server_identity(&addr);
+                    if let Some(identity) = &identity {
+                        connection.set_certificate(identity);
+                    }
+
+                    let host = host.clone();
+                    let validator = validator.clone();
+                    let tls_error_clone = tls_error_clone.clone();
+                    let validated = validated.clone();
+                    connection.connect_accept_certificate(move |_this, cert, cert_flags| {
+                        validated.set(true);
+                        match validator
+                            .borrow_mut()
+                            .validate(&host, port, cert, cert_flags)
+                        {
+                            Ok(()) => true,
+                            Err(e) => {
+                                tls_error_clone.replace(Some(e));
+                                false
+                            }
+                        }
+                    });
+                }
+            });
+        }
+
+        // Open the connection, without checking for errors
+        let connection = socket.connect_future(&connectable).await;
+
+        // Handle the custom tls errors, before handling the automatic iostream errors
+        if let Some(e) = tls_error.borrow().as_ref() {
+            return Err(Error::Tls {
+                host: endpoint.host,
+                port: endpoint.port,
+                error: *e,
+            });
+        };
+
+        let connection = connection.map_err(|e| Error::Gio(e.to_string()))?;
+        let stream = connection
+            .into_async_read_write()
+            .map_err(|_| Error::Gio("The connection streams aren't pollable".into()))?;
+        Ok(Box::new(GioConnection(stream)))
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(
+        &self,
+        endpoint: Endpoint,
+    ) -> LocalBoxFuture<'_, Result<Box<dyn Connection>, Error>> {
+        Box::pin(self.connect_tcp(endpoint))
+    }
+}
+
+// WARNING: The socket connection MUST STAY IN SCOPE while the body is read. If the connection
+// goes out of scope, it gets closed and reading it becomes impossible. The stream keeps it alive.
+struct GioConnection(gio::IOStreamAsyncReadWrite<gio::SocketConnection>);
+
+fn suppress_tls_connection_closed_error(
+    res: Result<usize, std::io::Error>,
+) -> Result<usize, std::io::Error> {
+    res.or_else(|e| {
+        if e.kind() == std::io::ErrorKind::Other {
+            let inner = e.into_inner().map(|e| e.downcast()).unwrap();
+            inner
+                .and_then(|gio_err: Box<glib::error::Error>| {
+                    match gio_err.kind::<gio::TlsError>() {
+                        // map the error to an equivalent read of 0 bytes, which will signal the end of the
+                        // connection
+                        Some(gio::TlsError::Eof) => {
+                            debug!("suppressed gio tls eof error");
+                            Ok(0)
+                        }
+                        _ => Err(gio_err.into()),
+                    }
+                })
+                .or_else(|e| Err(std::io::Error::other(e)))
+        } else {
+            Err(e)
+        }
+    })
+}
+
+impl AsyncRead for GioConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.0)
+            .poll_read(cx, buf)
+            .map(suppress_tls_connection_closed_error)
+    }
+}
+
+impl AsyncWrite for GioConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+type Handler = Rc<dyn Fn(MemoryStream) -> LocalBoxFuture<'static, ()>>;
+
+/// Transport connecting to servers running in the same process, to use the client offline.
+///
+/// Each connection is handed to the handler serving its host and port, which is spawned on
+/// the thread default main context.
+#[derive(Default, Clone)]
+pub struct MemoryTransport {
+    servers: HashMap<(String, u16), (Option<gio::TlsCertificate>, Handler)>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Serves the connections to `host:port` with `handler`. On tls connections, the client
+    /// validator is asked to validate `certificate`, refusing the connection if it's missing
+    pub fn serve<F, Fut>(
+        mut self,
+        host: &str,
+        port: u16,
+        certificate: Option<gio::TlsCertificate>,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(MemoryStream) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let handler: Handler = Rc::new(move |stream| handler(stream).boxed_local());
+        self.servers
+            .insert((host.to_owned(), port), (certificate, handler));
+        self
+    }
+    async fn connect_memory(&self, endpoint: Endpoint) -> Result<Box<dyn Connection>, Error> {
+        let refused = || {
+            Error::Gio(format!(
+                "Connection refused by {}:{}",
+                endpoint.host, endpoint.port
+            ))
+        };
+        let (certificate, handler) = self
+            .servers
+            .get(&(endpoint.host.clone(), endpoint.port))
+            .ok_or_else(refused)?;
+        if let Some(tls) = &endpoint.tls {
+            let certificate = certificate.as_ref().ok_or_else(refused)?;
+            // There's no trust store for the memory servers
+            let identity = gio::NetworkAddress::new(&endpoint.host, endpoint.port);
+            let errors = certificate.verify(Some(&identity), None::<&gio::TlsCertificate>)
+                | gio::TlsCertificateFlags::UNKNOWN_CA;
+            tls.validator
+                .borrow_mut()
+                .validate(&endpoint.host, endpoint.port, certificate, errors)
+                .map_err(|error| Error::Tls {
+                    host: endpoint.host.clone(),
+                    port: endpoint.port,
+                    error,
+                })?;
+            tls.peer_certificate.replace(Some(certificate.clone()));
+        }
+
+        let (client, server) = duplex();
+        glib::MainContext::ref_thread_default().spawn_local(handler(server));
+        Ok(Box::new(client))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn connect(
+        &self,
+        endpoint: Endpoint,
+    ) -> LocalBoxFuture<'_, Result<Box<dyn Connection>, Error>> {
+        Box::pin(self.connect_memory(endpoint))
+    }
+}
+
+/// One end of an in-memory connection created by [`duplex`]. Closing it, or dropping it,
+/// ends the stream read by the other end
+#[derive(Debug)]
+pub struct MemoryStream {
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+/// Creates a pair of connected streams: what's written on one end is read from the other
+pub fn duplex() -> (MemoryStream, MemoryStream) {
+    let (tx_a, rx_b) = mpsc::unbounded();
+    let (tx_b, rx_a) = mpsc::unbounded();
+    let stream = |tx, rx| MemoryStream {
+        tx: Some(tx),
+        rx,
+        chunk: vec![],
+        pos: 0,
+    };
+    (stream(tx_a, rx_a), stream(tx_b, rx_b))
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        while self.pos == self.chunk.len() {
+            match futures::ready!(self.rx.poll_next_unpin(cx)) {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let sent = self
+            .tx
+            .as_ref()
+            .is_some_and(|tx| tx.unbounded_send(buf.to_vec()).is_ok());
+        Poll::Ready(if sent {
+            Ok(buf.len())
+        } else {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        })
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplex_streams() -> Result<(), std::io::Error> {
+        glib::MainContext::new().block_on(async {
+            let (mut a, mut b) = duplex();
+            a.write_all(b"hello ").await?;
+            a.write_all(b"world").await?;
+            a.close().await?;
+
+            let mut read = String::new();
+            b.read_to_string(&mut read).await?;
+            assert_eq!(read, "hello world");
+
+            b.write_all(b"back").await?;
+            drop(b);
+            let mut read = vec![];
+            a.read_to_end(&mut read).await?;
+            assert_eq!(read, b"back");
+            Ok(())
+        })
+    }
+}
@@ -20,6 +20,12 @@ pub static DATA_DIR_PATH: Lazy<std::path::PathBuf> =
 pub static KNOWN_HOSTS_PATH: Lazy<std::path::PathBuf> =
     Lazy::new(|| DATA_DIR_PATH.join("known_hosts"));
 
+pub static TRUST_OVERRIDES_PATH: Lazy<std::path::PathBuf> =
+    Lazy::new(|| DATA_DIR_PATH.join("trust_overrides"));
+
+pub static IDENTITIES_PATH: Lazy<std::path::PathBuf> =
+    Lazy::new(|| DATA_DIR_PATH.join("identities"));
+
 pub static CONFIG_DIR_PATH: Lazy<std::path::PathBuf> =
     Lazy::new(|| glib::user_config_dir().join("geopard"));
 
@@ -54,6 +60,14 @@ pub fn bookmarks_url() -> Url {
     Url::parse(&format!("file://{}", BOOKMARK_FILE_PATH.to_str().unwrap())).unwrap()
 }
 
+/// Local date and time of a unix time, as in `2024-01-31 12:00:00`
+pub fn format_time(unix: i64) -> String {
+    glib::DateTime::from_unix_local(unix)
+        .and_then(|date| date.format("%F %T"))
+        .map(String::from)
+        .unwrap_or_else(|_| unix.to_string())
+}
+
 pub fn glibctx() -> glib::MainContext {
     glib::MainContext::default()
 }
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
@@ -5,12 +7,17 @@ pub use crate::build_config::*;
 
 pub static DEFAULT_CONFIG: Lazy<Config> = Lazy::new(|| Config {
     colors: true,
+    cache_size: Some(crate::response_cache::DEFAULT_CACHE_SIZE),
+    cache_ttl: Some(crate::response_cache::DEFAULT_CACHE_TTL.as_secs()),
     fonts: Fonts {
         paragraph: Some(Fonts::default_paragraph()),
         preformatted: Some(Fonts::default_preformatted()),
         heading: Some(Fonts::default_heading()),
         quote: Some(Fonts::default_quote()),
     },
+    proxies: HashMap::new(),
+    proxy: None,
+    validation: HashMap::new(),
 });
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -61,5 +68,21 @@ impl Fonts {
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Config {
     pub colors: bool,
+    /// Maximum size of the pages kept in memory by each window, in bytes
+    #[serde(default)]
+    pub cache_size: Option<usize>,
+    /// Seconds after which a page kept in memory is loaded again
+    #[serde(default)]
+    pub cache_ttl: Option<u64>,
     pub fonts: Fonts,
+    /// Gemini proxies (`host[:port]`) used to open the urls of other schemes, like `http`
+    #[serde(default)]
+    pub proxies: HashMap<String, String>,
+    /// SOCKS5 proxy used for every connection, e.g. `socks5h://127.0.0.1:9050` for Tor
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Certificate validation by `host` or `host:port`: `tofu`, `ca-or-tofu` or `ca`. The other
+    /// hosts use `ca-or-tofu`
+    #[serde(default)]
+    pub validation: HashMap<String, String>,
 }
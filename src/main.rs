@@ -2,8 +2,9 @@
 mod build_config;
 mod common;
 mod config;
-mod lossy_text_read;
+mod response_cache;
 mod session_provider;
+mod text_decoder;
 mod widgets;
 
 use std::cell::RefCell;
@@ -20,7 +21,7 @@ use log::error;
 
 use crate::common::{
     BOOKMARK_FILE_PATH, CONFIG_DIR_PATH, DATA_DIR_PATH, DEFAULT_BOOKMARKS, HISTORY_FILE_PATH,
-    SETTINGS_FILE_PATH,
+    IDENTITIES_PATH, SETTINGS_FILE_PATH,
 };
 
 async fn read_config() -> anyhow::Result<config::Config> {
@@ -62,6 +63,15 @@ async fn create_base_files() -> anyhow::Result<()> {
 
     create_dir_if_not_exists(&DATA_DIR_PATH).await?;
     create_dir_if_not_exists(&CONFIG_DIR_PATH).await?;
+    create_dir_if_not_exists(&IDENTITIES_PATH).await?;
+    // The identities contain private keys
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        async_fs::set_permissions(&*IDENTITIES_PATH, std::fs::Permissions::from_mode(0o700))
+            .await
+            .context("Failed to restrict the identities directory")?;
+    }
     init_file_if_not_exists(&BOOKMARK_FILE_PATH, Some(DEFAULT_BOOKMARKS.as_bytes())).await?;
     init_file_if_not_exists(&HISTORY_FILE_PATH, None).await?;
     init_file_if_not_exists(&SETTINGS_FILE_PATH, Some(default_config.as_bytes())).await?;
@@ -138,6 +148,7 @@ fn main() {
         ("win.new-tab", &["<Ctrl>t"]),
         ("win.close-tab", &["<Ctrl>w"]),
         ("win.focus-url-bar", &["F6", "<Ctrl>L"]),
+        ("win.page-info", &["<Ctrl>i"]),
         ("win.zoom-in", &["<Ctrl>plus"]),
         ("win.zoom-out", &["<Ctrl>minus"]),
         ("win.reset-zoom", &["<Ctrl>0"]),
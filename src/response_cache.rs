@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+/// Default maximum size of the cached bodies, in bytes
+pub const DEFAULT_CACHE_SIZE: usize = 32 * 1024 * 1024;
+/// Default time after which a cached body is loaded again
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Body of a gemini page, already decoded to utf-8
+#[derive(Debug, Clone)]
+pub struct CachedPage {
+    pub body: Rc<[u8]>,
+    /// Languages of the page, from the mime type of its response
+    pub lang: Option<String>,
+}
+
+struct Entry {
+    page: CachedPage,
+    inserted: Instant,
+    /// Value of the cache clock when the entry was last used
+    last_used: u64,
+}
+
+/// Bodies of the pages loaded in a session, by normalized url.
+///
+/// The least recently used bodies are evicted once their total size exceeds the budget, and
+/// each body is dropped once it's older than the ttl. The urls redirected to a cached page
+/// share its entry.
+pub struct ResponseCache {
+    entries: HashMap<String, Entry>,
+    /// Key of the entry each redirected url leads to
+    redirects: HashMap<String, String>,
+    size: usize,
+    budget: usize,
+    ttl: Duration,
+    clock: u64,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_SIZE, DEFAULT_CACHE_TTL)
+    }
+}
+
+impl std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("entries", &self.entries.len())
+            .field("size", &self.size)
+            .field("budget", &self.budget)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl ResponseCache {
+    pub fn new(budget: usize, ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            redirects: HashMap::new(),
+            size: 0,
+            budget,
+            ttl,
+            clock: 0,
+        }
+    }
+    /// Changes the budget and the ttl, evicting the bodies that don't fit anymore
+    pub fn set_limits(&mut self, budget: usize, ttl: Duration) {
+        self.budget = budget;
+        self.ttl = ttl;
+        self.evict();
+    }
+    /// Key of `url`, without its fragment and the default gemini port
+    fn key(url: &Url) -> String {
+        let mut url = url.clone();
+        url.set_fragment(None);
+        if url.scheme() == "gemini" && url.port() == Some(1965) {
+            let _ = url.set_port(None);
+        }
+        url.to_string()
+    }
+    pub fn get(&mut self, url: &Url) -> Option<CachedPage> {
+        let mut key = Self::key(url);
+        if let Some(target) = self.redirects.get(&key) {
+            key = target.clone();
+        }
+        let entry = self.entries.get_mut(&key)?;
+        if entry.inserted.elapsed() > self.ttl {
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.page.body.len();
+            }
+            return None;
+        }
+        self.clock += 1;
+        entry.last_used = self.clock;
+        Some(entry.page.clone())
+    }
+    /// Caches `page`, replacing the previous page of `url`. A body bigger than the whole
+    /// budget isn't cached
+    pub fn insert(&mut self, url: &Url, page: CachedPage) {
+        self.remove(url);
+        if page.body.len() > self.budget {
+            return;
+        }
+        self.clock += 1;
+        self.size += page.body.len();
+        self.entries.insert(
+            Self::key(url),
+            Entry {
+                page,
+                inserted: Instant::now(),
+                last_used: self.clock,
+            },
+        );
+        self.evict();
+    }
+    /// Makes `from` lead to the cached page of `to`, which it redirected to
+    pub fn insert_redirect(&mut self, from: &Url, to: &Url) {
+        let (from, to) = (Self::key(from), Self::key(to));
+        if from != to && self.entries.contains_key(&to) {
+            if let Some(entry) = self.entries.remove(&from) {
+                self.size -= entry.page.body.len();
+            }
+            self.redirects.insert(from, to);
+        }
+    }
+    pub fn remove(&mut self, url: &Url) {
+        let key = Self::key(url);
+        self.redirects.remove(&key);
+        if let Some(entry) = self.entries.remove(&key) {
+            self.size -= entry.page.body.len();
+        }
+    }
+    /// Drops the expired bodies, then the least recently used ones until the rest fits
+    fn evict(&mut self) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, entry| entry.inserted.elapsed() <= ttl);
+        self.size = self
+            .entries
+            .values()
+            .map(|entry| entry.page.body.len())
+            .sum();
+        let entries = &self.entries;
+        self.redirects
+            .retain(|_, target| entries.contains_key(target));
+        while self.size > self.budget {
+            let Some(key) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.page.body.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Opens `url` like a tab does, returning the number of requests sent
+    fn open(cache: &mut ResponseCache, url: &str) -> usize {
+        let url = Url::parse(url).unwrap();
+        if cache.get(&url).is_some() {
+            return 0;
+        }
+        let page = CachedPage {
+            body: b"# Page\n".as_slice().into(),
+            lang: None,
+        };
+        cache.insert(&url, page);
+        1
+    }
+
+    #[test]
+    fn shared_between_tabs() {
+        let mut cache = ResponseCache::default();
+        let requests = open(&mut cache, "gemini://example.org/page.gmi")
+            + open(&mut cache, "gemini://example.org:1965/page.gmi#section");
+        assert_eq!(requests, 1);
+
+        // The stale bodies are loaded again
+        cache.set_limits(DEFAULT_CACHE_SIZE, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(open(&mut cache, "gemini://example.org/page.gmi"), 1);
+    }
+
+    #[test]
+    fn redirects() {
+        let mut cache = ResponseCache::default();
+        let old = Url::parse("gemini://example.org/old").unwrap();
+        let new = Url::parse("gemini://example.org/new").unwrap();
+        assert_eq!(open(&mut cache, new.as_str()), 1);
+        cache.insert_redirect(&old, &new);
+        assert_eq!(open(&mut cache, old.as_str()), 0);
+
+        // Changing the target changes the redirected url too
+        cache.remove(&new);
+        assert!(cache.get(&old).is_none());
+    }
+}
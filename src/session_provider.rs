@@ -1,40 +1,302 @@
 use std::cell::{Ref, RefCell};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::rc::Rc;
+use std::time::Duration;
 
 use adw::subclass::prelude::BinImpl;
-use gemini::known_hosts::KnownHostsRepo;
-use gemini::{CertificateError, ClientBuilder};
+use anyhow::Context;
+use gemini::identity::IdentityStore;
+use gemini::known_hosts::overrides::{TrustOverride, TrustOverrides};
+use gemini::known_hosts::{
+    self, interop, KnownHost, KnownHostsFile, KnownHostsMap, KnownHostsRepo, ValidationMode,
+};
+use gemini::{CertificateError, ClientBuilder, RedirectPolicy};
 use gtk::prelude::*;
 use gtk::subclass::prelude::*;
 use gtk::{gio, glib};
+use url::Url;
 
 use crate::common;
+use crate::response_cache::{CachedPage, ResponseCache, DEFAULT_CACHE_SIZE, DEFAULT_CACHE_TTL};
+
+/// Known hosts store of another gemini client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TofuStore {
+    Lagrange,
+    Amfora,
+    /// Only available with the `av98` feature, as it needs sqlite
+    #[cfg(feature = "av98")]
+    Av98,
+}
+
+impl TofuStore {
+    pub const ALL: &'static [TofuStore] = &[
+        TofuStore::Lagrange,
+        TofuStore::Amfora,
+        #[cfg(feature = "av98")]
+        TofuStore::Av98,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            TofuStore::Lagrange => "Lagrange",
+            TofuStore::Amfora => "Amfora",
+            #[cfg(feature = "av98")]
+            TofuStore::Av98 => "AV-98",
+        }
+    }
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            TofuStore::Lagrange => "trusted.2.txt",
+            TofuStore::Amfora => "tofu.toml",
+            #[cfg(feature = "av98")]
+            TofuStore::Av98 => "tofu.db",
+        }
+    }
+    /// AV-98 stores the certificates by ip address, they can't be exported
+    pub fn can_export(&self) -> bool {
+        matches!(self, TofuStore::Lagrange | TofuStore::Amfora)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct CertificateValidator {
     overridden_hosts: Rc<RefCell<HashSet<String>>>,
-    known_hosts_file: Rc<RefCell<gemini::known_hosts::KnownHostsFile>>,
+    known_hosts: Rc<RefCell<dyn KnownHostsRepo>>,
+    /// Validation mode by `host` or `host:port`
+    modes: Rc<RefCell<HashMap<String, ValidationMode>>>,
+    trust_overrides: Rc<RefCell<TrustOverrides>>,
+    /// Reloads the trust overrides when they are changed by another window
+    _overrides_monitor: Option<gio::FileMonitor>,
+    /// Fingerprint of the last certificate refused for each host
+    refused: Rc<RefCell<HashMap<String, String>>>,
 }
 
 impl CertificateValidator {
-    pub fn new(file: std::fs::File) -> Self {
+    /// Uses the known hosts stored at `path` and the trust overrides stored at
+    /// `overrides_path`. If they can't be loaded, they are only remembered for this session,
+    /// and the files are left untouched
+    pub fn new(path: &Path, overrides_path: &Path) -> Self {
+        let known_hosts: Rc<RefCell<dyn KnownHostsRepo>> = match KnownHostsFile::open(path) {
+            Ok(file) => Rc::new(RefCell::new(file)),
+            Err(e) => {
+                log::error!("Failed to load the known hosts: {}", e);
+                Rc::new(RefCell::new(KnownHostsMap::new()))
+            }
+        };
+        let trust_overrides = TrustOverrides::open(overrides_path).unwrap_or_else(|e| {
+            log::error!("Failed to load the trust overrides: {}", e);
+            TrustOverrides::new()
+        });
+        let trust_overrides = Rc::new(RefCell::new(trust_overrides));
+        let overrides_monitor = match gio::File::for_path(overrides_path)
+            .monitor_file(gio::FileMonitorFlags::NONE, None::<&gio::Cancellable>)
+        {
+            Ok(monitor) => {
+                let trust_overrides = Rc::downgrade(&trust_overrides);
+                monitor.connect_changed(move |_, _, _, _| {
+                    let Some(trust_overrides) = trust_overrides.upgrade() else {
+                        return;
+                    };
+                    if let Err(e) = trust_overrides.borrow_mut().refresh() {
+                        log::warn!("Failed to reload the trust overrides: {}", e);
+                    }
+                });
+                Some(monitor)
+            }
+            Err(e) => {
+                log::warn!("Failed to monitor the trust overrides: {}", e);
+                None
+            }
+        };
         Self {
             overridden_hosts: Default::default(),
-            known_hosts_file: Rc::new(RefCell::new(gemini::known_hosts::KnownHostsFile::new(file))),
+            known_hosts,
+            modes: Default::default(),
+            trust_overrides,
+            _overrides_monitor: overrides_monitor,
+            refused: Default::default(),
         }
     }
-    pub fn validate(&self, host: &str, sha: &gio::TlsCertificate) -> Result<(), CertificateError> {
-        if self.overridden_hosts.borrow().contains(host) {
+    pub fn validate(
+        &self,
+        host: &str,
+        port: u16,
+        sha: &gio::TlsCertificate,
+        errors: gio::TlsCertificateFlags,
+    ) -> Result<(), CertificateError> {
+        if self.is_overridden(host, port) {
+            return Ok(());
+        }
+        let key = known_hosts::host_key(host, port);
+        let fingerprint = sha
+            .certificate()
+            .map(|der| known_hosts::fingerprint(&der))
+            .unwrap_or_default();
+        if self
+            .trust_overrides
+            .borrow()
+            .allows(&key, &fingerprint, glib::real_time() / 1_000_000)
+        {
             return Ok(());
         }
-        gemini::known_hosts::validate(&mut *self.known_hosts_file.borrow_mut(), host, sha)
+        let res = known_hosts::validate_with_mode(
+            &mut *self.known_hosts.borrow_mut(),
+            host,
+            port,
+            sha,
+            errors,
+            self.mode(host, port),
+        );
+        if res.is_err() {
+            self.refused.borrow_mut().insert(key, fingerprint);
+        }
+        res
+    }
+    /// Sets the validation modes by `host` or `host:port`, skipping the invalid ones
+    pub fn set_modes(&self, modes: &HashMap<String, String>) {
+        let modes = modes
+            .iter()
+            .filter_map(|(host, mode)| match mode.parse() {
+                Ok(mode) => Some((host.clone(), mode)),
+                Err(e) => {
+                    log::error!("Invalid certificate validation for {}: {}", host, e);
+                    None
+                }
+            })
+            .collect();
+        self.modes.replace(modes);
+    }
+    /// Validation mode of `host:port`, [`ValidationMode::CaOrTofu`] unless configured otherwise
+    pub fn mode(&self, host: &str, port: u16) -> ValidationMode {
+        let modes = self.modes.borrow();
+        modes
+            .get(&known_hosts::host_key(host, port))
+            .or_else(|| modes.get(host))
+            .copied()
+            .unwrap_or_default()
+    }
+    /// Whether the certificate of `host:port` is trusted for this session, whatever it is
+    pub fn is_overridden(&self, host: &str, port: u16) -> bool {
+        self.overridden_hosts
+            .borrow()
+            .contains(&known_hosts::host_key(host, port))
+    }
+    /// Certificate trusted for `host:port`
+    pub fn known(&self, host: &str, port: u16) -> Option<KnownHost> {
+        self.known_hosts
+            .borrow()
+            .get(&known_hosts::host_key(host, port))
+            .cloned()
+    }
+    pub fn override_trust(&self, host: &str, port: u16) {
+        self.overridden_hosts
+            .borrow_mut()
+            .insert(known_hosts::host_key(host, port));
+    }
+    pub fn remove_known(&self, host: &str, port: u16) {
+        self.forget(&known_hosts::host_key(host, port));
+    }
+    /// Keys of the hosts trusted for this session, sorted
+    pub fn overridden_hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self.overridden_hosts.borrow().iter().cloned().collect();
+        hosts.sort();
+        hosts
+    }
+    pub fn remove_override(&self, key: &str) {
+        self.overridden_hosts.borrow_mut().remove(key);
+    }
+    /// Trusts the last certificate refused for `host:port` whatever its errors, for `days` or
+    /// forever. Returns false if no certificate was refused
+    pub fn persist_override(&self, host: &str, port: u16, days: Option<i64>) -> bool {
+        let key = known_hosts::host_key(host, port);
+        let Some(fingerprint) = self.refused.borrow().get(&key).cloned() else {
+            return false;
+        };
+        let trust_override = TrustOverride {
+            fingerprint,
+            until: days.map(|days| glib::real_time() / 1_000_000 + days * 86400),
+        };
+        if let Err(e) = self
+            .trust_overrides
+            .borrow_mut()
+            .insert(&key, trust_override)
+        {
+            log::error!("Failed to save the trust override of {}: {}", key, e);
+        }
+        true
     }
-    pub fn override_trust(&self, host: &str) {
-        self.overridden_hosts.borrow_mut().insert(host.to_owned());
+    /// Certificate of `host:port` trusted despite its errors, if any
+    pub fn persistent_override(&self, host: &str, port: u16) -> Option<TrustOverride> {
+        self.trust_overrides
+            .borrow()
+            .get(&known_hosts::host_key(host, port))
+            .cloned()
     }
-    pub fn remove_known(&self, host: &str) {
-        self.known_hosts_file.borrow_mut().remove(host);
+    /// Certificates trusted despite their errors by host key, sorted
+    pub fn persistent_overrides(&self) -> Vec<(String, TrustOverride)> {
+        self.trust_overrides
+            .borrow()
+            .iter()
+            .map(|(key, trust_override)| (key.to_owned(), trust_override.clone()))
+            .collect()
+    }
+    pub fn remove_persistent_override(&self, key: &str) {
+        if let Err(e) = self.trust_overrides.borrow_mut().remove(key) {
+            log::error!("Failed to remove the trust override of {}: {}", key, e);
+        }
+    }
+    /// Known hosts by key, including the ones saved by other windows
+    pub fn known_hosts(&self) -> HashMap<String, KnownHost> {
+        let mut known_hosts = self.known_hosts.borrow_mut();
+        if let Err(e) = known_hosts.refresh() {
+            log::warn!("Failed to reload the known hosts: {}", e);
+        }
+        known_hosts.values()
+    }
+    /// Forgets the certificate of the host with `key`, trusting the next one seen
+    pub fn forget(&self, key: &str) {
+        if let Err(e) = self.known_hosts.borrow_mut().remove(key) {
+            log::error!("Failed to forget the certificate of {}: {}", key, e);
+        }
+    }
+    /// Adds the hosts stored by another client at `path`, returning the number of new hosts
+    pub fn import(&self, store: TofuStore, path: &Path) -> anyhow::Result<usize> {
+        let mut known_hosts = self.known_hosts.borrow_mut();
+        let added = match store {
+            TofuStore::Lagrange => interop::import_lagrange(
+                &mut *known_hosts,
+                &std::fs::read_to_string(path).context("Reading the Lagrange known hosts")?,
+            )?,
+            TofuStore::Amfora => interop::import_amfora(
+                &mut *known_hosts,
+                &std::fs::read_to_string(path).context("Reading the Amfora known hosts")?,
+            )?,
+            #[cfg(feature = "av98")]
+            TofuStore::Av98 => interop::import_av98(&mut *known_hosts, path)?,
+        };
+        Ok(added)
+    }
+    /// Writes the known hosts at `path`, in the format of another client
+    pub fn export(&self, store: TofuStore, path: &Path) -> anyhow::Result<()> {
+        let known_hosts = self.known_hosts.borrow();
+        let text = match store {
+            TofuStore::Lagrange => interop::export_lagrange(&*known_hosts),
+            TofuStore::Amfora => interop::export_amfora(&*known_hosts),
+            #[cfg(feature = "av98")]
+            TofuStore::Av98 => anyhow::bail!("The AV-98 known hosts can't be exported"),
+        };
+        std::fs::write(path, text).context("Writing the exported known hosts")?;
+        Ok(())
+    }
+    pub fn set_pinned(&self, key: &str, pinned: bool) {
+        let mut known_hosts = self.known_hosts.borrow_mut();
+        let Some(known) = known_hosts.get(key).cloned() else {
+            return;
+        };
+        if let Err(e) = known_hosts.insert(key, KnownHost { pinned, ..known }) {
+            log::error!("Failed to pin the certificate of {}: {}", key, e);
+        }
     }
 }
 
@@ -45,7 +307,10 @@ pub mod imp {
     #[derive(Debug, Default)]
     pub struct SessionProvider {
         pub(crate) validator: Rc<RefCell<Option<CertificateValidator>>>,
+        pub(crate) identities: Rc<RefCell<IdentityStore>>,
         pub(crate) client: RefCell<gemini::Client>,
+        /// Pages loaded by the tabs of the window
+        pub(crate) cache: RefCell<ResponseCache>,
     }
 
     #[glib::object_subclass]
@@ -58,23 +323,55 @@ pub mod imp {
     impl ObjectImpl for SessionProvider {
         fn constructed(&self) {
             self.parent_constructed();
-            let cr = CertificateValidator::new(
-                std::fs::OpenOptions::new()
-                    .read(true)
-                    .create(true)
-                    .append(true)
-                    .open(&*common::KNOWN_HOSTS_PATH)
-                    .unwrap(),
-            );
-            let cr_clone = cr.clone();
-            let client = ClientBuilder::new()
-                .redirect(true)
-                .validator(move |host: &str, sha: &gio::TlsCertificate| {
-                    cr_clone.validate(host, sha)
-                })
-                .build();
+            let cr =
+                CertificateValidator::new(&common::KNOWN_HOSTS_PATH, &common::TRUST_OVERRIDES_PATH);
+            match IdentityStore::open(&common::IDENTITIES_PATH) {
+                Ok(identities) => {
+                    self.identities.replace(identities);
+                }
+                Err(e) => log::error!("Failed to load the identities: {}", e),
+            }
+
             self.validator.replace(Some(cr));
-            self.client.replace(client);
+            self.client
+                .replace(self.build_client(&crate::config::Config::default()));
+        }
+    }
+    impl SessionProvider {
+        pub(crate) fn build_client(&self, config: &crate::config::Config) -> gemini::Client {
+            let cr = self.validator.borrow().clone().unwrap();
+            cr.set_modes(&config.validation);
+            let identities = self.identities.clone();
+            let mut builder = ClientBuilder::new()
+                .redirect(true)
+                .cross_host_redirects(RedirectPolicy::Stop)
+                .cross_scheme_redirects(RedirectPolicy::Stop)
+                .validator(
+                    move |host: &str,
+                          port: u16,
+                          sha: &gio::TlsCertificate,
+                          errors: gio::TlsCertificateFlags| {
+                        cr.validate(host, port, sha, errors)
+                    },
+                )
+                .identity_provider(move |url: &Url| {
+                    let identities = identities.borrow();
+                    let identity = identities.find(url)?;
+                    identity
+                        .certificate()
+                        .map_err(|e| log::error!("Invalid identity {}: {}", identity.name(), e))
+                        .ok()
+                });
+            for (scheme, proxy) in &config.proxies {
+                builder = builder.scheme_proxy(scheme, proxy);
+            }
+            if let Some(proxy) = &config.proxy {
+                match proxy.parse() {
+                    Ok(proxy) => builder = builder.proxy(proxy),
+                    Err(e) => log::error!("{}", e),
+                }
+            }
+            builder.build()
         }
     }
     impl WidgetImpl for SessionProvider {}
@@ -103,10 +400,38 @@ impl SessionProvider {
             .downcast::<SessionProvider>()
             .ok()
     }
+    pub fn set_config(&self, config: &crate::config::Config) {
+        let imp = self.imp();
+        imp.client.replace(imp.build_client(config));
+        imp.cache.borrow_mut().set_limits(
+            config.cache_size.unwrap_or(DEFAULT_CACHE_SIZE),
+            config
+                .cache_ttl
+                .map_or(DEFAULT_CACHE_TTL, Duration::from_secs),
+        );
+    }
+    /// Cached body of `url`, if it's still fresh
+    pub fn cached(&self, url: &Url) -> Option<CachedPage> {
+        self.imp().cache.borrow_mut().get(url)
+    }
+    pub fn cache_page(&self, url: &Url, page: CachedPage) {
+        self.imp().cache.borrow_mut().insert(url, page);
+    }
+    /// Keeps the cached body of `to` for `from`, which redirected to it
+    pub fn cache_redirect(&self, from: &Url, to: &Url) {
+        self.imp().cache.borrow_mut().insert_redirect(from, to);
+    }
+    /// Drops the cached body of `url`, once it has changed
+    pub fn uncache_page(&self, url: &Url) {
+        self.imp().cache.borrow_mut().remove(url);
+    }
     pub fn client(&self) -> Ref<gemini::Client> {
         self.imp().client.borrow()
     }
     pub fn validator(&self) -> Ref<CertificateValidator> {
         Ref::map(self.imp().validator.borrow(), |v| v.as_ref().unwrap())
     }
+    pub fn identities(&self) -> Rc<RefCell<IdentityStore>> {
+        self.imp().identities.clone()
+    }
 }
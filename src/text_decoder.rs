@@ -0,0 +1,57 @@
+use encoding_rs::{CoderResult, Decoder, Encoding, UTF_8};
+use futures::prelude::*;
+
+/// Reads the lines of a text encoded in any charset known by `encoding_rs`, decoding them to
+/// utf-8. Invalid sequences are replaced with U+FFFD
+pub struct TextDecoder<T> {
+    reader: T,
+    decoder: Decoder,
+    bytes: Vec<u8>,
+    finished: bool,
+}
+
+impl<T: AsyncBufRead + Unpin> TextDecoder<T> {
+    /// Decodes `reader` using the `charset` label, falling back to utf-8 for unknown labels
+    pub fn new(reader: T, charset: &str) -> Self {
+        let encoding = Encoding::for_label(charset.trim().as_bytes()).unwrap_or_else(|| {
+            log::warn!("Unknown charset {:?}, decoding as utf-8", charset);
+            UTF_8
+        });
+        Self {
+            reader,
+            decoder: encoding.new_decoder_with_bom_removal(),
+            bytes: Vec::with_capacity(1024),
+            finished: false,
+        }
+    }
+    /// Appends the next line to `buf`, returning the number of bytes appended. Returns 0 at the
+    /// end of the text
+    pub async fn read_line(&mut self, buf: &mut String) -> std::io::Result<usize> {
+        let start = buf.len();
+        // A line can decode to nothing if it ends in the middle of a character, as with utf-16
+        while buf.len() == start && !self.finished {
+            self.bytes.clear();
+            self.reader.read_until(b'\n', &mut self.bytes).await?;
+            // Only the last line doesn't end with a newline
+            self.finished = !self.bytes.ends_with(b"\n");
+
+            let mut read = 0;
+            loop {
+                let remaining = self.bytes.len() - read;
+                buf.reserve(
+                    self.decoder
+                        .max_utf8_buffer_length(remaining)
+                        .unwrap_or(remaining * 3 + 16),
+                );
+                let (res, n, _) =
+                    self.decoder
+                        .decode_to_string(&self.bytes[read..], buf, self.finished);
+                read += n;
+                if res == CoderResult::InputEmpty {
+                    break;
+                }
+            }
+        }
+        Ok(buf.len() - start)
+    }
+}
@@ -1,3 +1,4 @@
+mod page_info;
 mod pages;
 #[allow(clippy::await_holding_refcell_ref)]
 mod tab;
@@ -0,0 +1,127 @@
+use adw::prelude::*;
+use gemini::known_hosts::{CertificateInfo, ValidationMode};
+use gtk::glib;
+
+use crate::common::format_time;
+use crate::session_provider::CertificateValidator;
+use crate::widgets::tab::PageInfo;
+
+fn row(title: &str, value: &str) -> adw::ActionRow {
+    let row = adw::ActionRow::builder()
+        .title(title)
+        .subtitle(glib::markup_escape_text(value))
+        .subtitle_selectable(true)
+        .build();
+    row.add_css_class("property");
+    row
+}
+
+fn format_size(size: usize) -> String {
+    if size < 1000 {
+        format!("{} B", size)
+    } else {
+        format!("{:.2} KB", size as f64 / 1000.0)
+    }
+}
+
+fn response_group(info: &PageInfo) -> adw::PreferencesGroup {
+    let group = adw::PreferencesGroup::builder().title("Response").build();
+    group.add(&row("Address", info.url.as_str()));
+    group.add(&row(
+        "Status",
+        &format!("{} {:?}", info.status.raw(), info.status.code()),
+    ));
+    if !info.meta.is_empty() {
+        group.add(&row("Meta", &info.meta));
+    }
+    if let Some(size) = info.size {
+        group.add(&row("Size", &format_size(size)));
+    }
+    group.add(&row(
+        "Response Time",
+        &format!("{} ms", info.header_time.as_millis()),
+    ));
+    if let Some(load_time) = info.load_time {
+        group.add(&row("Load Time", &format!("{} ms", load_time.as_millis())));
+    }
+    group
+}
+
+fn certificate_group(info: &PageInfo, validator: &CertificateValidator) -> adw::PreferencesGroup {
+    let group = adw::PreferencesGroup::builder()
+        .title("Certificate")
+        .build();
+    let Some(cert) = info
+        .certificate
+        .as_ref()
+        .and_then(CertificateInfo::from_certificate)
+    else {
+        group.set_description(Some("The page wasn't received over a secure connection"));
+        return group;
+    };
+
+    group.add(&row("SHA-256 Fingerprint", &cert.fingerprint));
+    if let Some(spki_fingerprint) = &cert.spki_fingerprint {
+        group.add(&row("Public Key Fingerprint", spki_fingerprint));
+    }
+    if let Some(subject) = &cert.subject {
+        group.add(&row("Subject", subject));
+    }
+    if let Some(not_before) = cert.not_before {
+        group.add(&row("Valid From", &format_time(not_before)));
+    }
+    if let Some(not_after) = cert.not_after {
+        group.add(&row("Valid Until", &format_time(not_after)));
+    }
+
+    let host = info.url.host_str().unwrap_or_default();
+    let port = info.url.port().unwrap_or(1965);
+    let known = validator
+        .known(host, port)
+        .filter(|known| known.fingerprint == cert.fingerprint);
+    if let Some(known) = &known {
+        group.add(&row("First Seen", &format_time(known.first_seen)));
+    }
+    let trust_override = validator
+        .persistent_override(host, port)
+        .filter(|trust_override| trust_override.fingerprint == cert.fingerprint);
+    let trust = if validator.is_overridden(host, port) {
+        "Overridden for this session".to_owned()
+    } else if let Some(trust_override) = trust_override {
+        match trust_override.until {
+            Some(until) => format!("Overridden until {}", format_time(until)),
+            None => "Always overridden".to_owned(),
+        }
+    } else if known.is_some() {
+        "Trusted on first use".to_owned()
+    } else {
+        "Not stored".to_owned()
+    };
+    group.add(&row("Trust", &trust));
+    let mode = match validator.mode(host, port) {
+        ValidationMode::Tofu => "Trust on first use",
+        ValidationMode::CaOrTofu => "Certificate authorities, then trust on first use",
+        ValidationMode::Ca => "Certificate authorities only",
+    };
+    group.add(&row("Validation", mode));
+    group
+}
+
+/// Dialog showing the details of the response displayed by a tab, and of the certificate of
+/// its server
+pub fn page_info_dialog(info: &PageInfo, validator: &CertificateValidator) -> adw::Dialog {
+    let page = adw::PreferencesPage::new();
+    page.add(&response_group(info));
+    page.add(&certificate_group(info, validator));
+
+    let toolbar_view = adw::ToolbarView::new();
+    toolbar_view.add_top_bar(&adw::HeaderBar::new());
+    toolbar_view.set_content(Some(&page));
+
+    adw::Dialog::builder()
+        .title("Page Info")
+        .content_width(480)
+        .content_height(560)
+        .child(&toolbar_view)
+        .build()
+}
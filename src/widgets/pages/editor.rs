@@ -0,0 +1,56 @@
+use gtk::subclass::prelude::*;
+use gtk::{glib, CompositeTemplate, TemplateChild};
+
+mod imp {
+    pub use super::*;
+    #[derive(CompositeTemplate, Default)]
+    #[template(resource = "/com/ranfdev/Geopard/ui/editor_page.ui")]
+    pub struct Editor {
+        #[template_child]
+        pub label: TemplateChild<gtk::Label>,
+        #[template_child]
+        pub text_view: TemplateChild<gtk::TextView>,
+        #[template_child]
+        pub mime_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub token_entry: TemplateChild<gtk::PasswordEntry>,
+        #[template_child]
+        pub upload_btn: TemplateChild<gtk::Button>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for Editor {
+        // `NAME` needs to match `class` attribute of template
+        const NAME: &'static str = "Editor";
+        type Type = super::Editor;
+        type ParentType = gtk::Box;
+
+        fn class_init(klass: &mut Self::Class) {
+            klass.bind_template();
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for Editor {}
+    impl WidgetImpl for Editor {}
+    impl BoxImpl for Editor {}
+}
+
+glib::wrapper! {
+    pub struct Editor(ObjectSubclass<imp::Editor>)
+    @extends gtk::Box, gtk::Widget;
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        glib::Object::new()
+    }
+}
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -13,14 +13,37 @@ use url::Url;
 
 use crate::config;
 
+// Schemes opened inside the browser, linked with a different arrow than the external ones
+const INTERNAL_SCHEMES: [&str; 6] = ["gemini", "gopher", "spartan", "finger", "nex", "about"];
+// Languages written right-to-left, and the scripts overriding the direction of a language tag
+const RTL_LANGUAGES: [&str; 12] = [
+    "ar", "ckb", "dv", "fa", "he", "iw", "ps", "sd", "syr", "ug", "ur", "yi",
+];
+const RTL_SCRIPTS: [&str; 6] = ["arab", "hebr", "syrc", "thaa", "nkoo", "adlm"];
+
+/// Whether the BCP47 tag `lang` is of a language written right-to-left
+fn is_rtl(lang: &str) -> bool {
+    let mut subtags = lang.split(['-', '_']).map(|s| s.to_ascii_lowercase());
+    let Some(primary) = subtags.next() else {
+        return false;
+    };
+    match subtags.find(|s| s.len() == 4) {
+        Some(script) => RTL_SCRIPTS.contains(&script.as_str()),
+        None => RTL_LANGUAGES.contains(&primary.as_str()),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Surface {
     text_view: gtk::TextView,
     config: crate::config::Config,
+    lang: Option<String>,
 }
 
 impl Surface {
-    pub fn new(config: crate::config::Config) -> Self {
+    /// `lang` is the `lang` parameter of the response, a comma separated list of languages.
+    /// The first one sets the direction of the page and the language of its text
+    pub fn new(config: crate::config::Config, lang: Option<&str>) -> Self {
         let text_view = gtk::TextView::builder()
             .top_margin(40)
             .bottom_margin(80)
@@ -35,7 +58,23 @@ impl Surface {
         let text_buffer = gtk::TextBuffer::new(None);
         text_view.set_buffer(Some(&text_buffer));
 
-        let mut this = Self { text_view, config };
+        let lang = lang
+            .and_then(|lang| lang.split(',').next())
+            .map(str::trim)
+            .filter(|lang| !lang.is_empty());
+        if let Some(lang) = lang {
+            text_view.set_direction(if is_rtl(lang) {
+                gtk::TextDirection::Rtl
+            } else {
+                gtk::TextDirection::Ltr
+            });
+        }
+
+        let mut this = Self {
+            text_view,
+            config,
+            lang: lang.map(str::to_owned),
+        };
         this.init_tags();
         this
     }
@@ -124,6 +163,22 @@ impl Surface {
         );
         tag_pre.set_wrap_mode(gtk::WrapMode::None);
 
+        // Applied to each paragraph, following its first strong character
+        let tag_rtl = gtk::TextTag::builder()
+            .name("rtl")
+            .direction(gtk::TextDirection::Rtl)
+            .build();
+        let tag_ltr = gtk::TextTag::builder()
+            .name("ltr")
+            .direction(gtk::TextDirection::Ltr)
+            .build();
+
+        if let Some(lang) = &self.lang {
+            for tag in [&tag_h1, &tag_h2, &tag_h3, &tag_q, &tag_p, &tag_a, &tag_pre] {
+                tag.set_language(Some(lang));
+            }
+        }
+
         tag_table.add(&tag_h1);
         tag_table.add(&tag_h2);
         tag_table.add(&tag_h3);
@@ -131,6 +186,8 @@ impl Surface {
         tag_table.add(&tag_p);
         tag_table.add(&tag_a);
         tag_table.add(&tag_pre);
+        tag_table.add(&tag_rtl);
+        tag_table.add(&tag_ltr);
         tag_table
     }
     fn create_tag(name: &str, config: &crate::config::Font) -> gtk::TextTag {
@@ -147,6 +204,22 @@ impl Surface {
     }
 }
 
+/// Sets the direction of the paragraph containing the text inserted from `start`, so that
+/// each paragraph of a mixed-direction page is aligned and reordered on its own. Preformatted
+/// paragraphs without strong characters stay left-to-right, to keep the ascii art readable
+fn apply_direction(buffer: &gtk::TextBuffer, start: i32, preformatted: bool) {
+    let mut start = buffer.iter_at_offset(start);
+    start.set_line_offset(0);
+    let end = buffer.end_iter();
+    let tag = match gtk::pango::find_base_dir(&buffer.text(&start, &end, false)) {
+        gtk::pango::Direction::Rtl => "rtl",
+        gtk::pango::Direction::Ltr => "ltr",
+        _ if preformatted => "ltr",
+        _ => return,
+    };
+    buffer.apply_tag_by_name(tag, &start, &end);
+}
+
 pub enum HypertextEvent {
     Title(String),
 }
@@ -296,7 +369,7 @@ impl Hypertext {
                         gemini::Tag::Link(url, label) => {
                             let link_char = if let Ok(true) = self
                                 .parse_link(url)
-                                .map(|url| ["gemini", "about"].contains(&url.scheme()))
+                                .map(|url| INTERNAL_SCHEMES.contains(&url.scheme()))
                             {
                                 "⇒"
                             } else {
@@ -316,6 +389,7 @@ impl Hypertext {
                                     &["p", "a"],
                                 );
                                 buffer.apply_tag(&tag, &buffer.iter_at_offset(start), &text_iter);
+                                apply_direction(&buffer, start, false);
 
                                 tag
                             };
@@ -324,6 +398,35 @@ impl Hypertext {
                                 .borrow_mut()
                                 .insert(tag.clone(), url.clone());
                         }
+                        gemini::Tag::Prompt(url, label) => {
+                            let text_view = self
+                                .imp()
+                                .surface
+                                .borrow()
+                                .as_ref()
+                                .unwrap()
+                                .text_view
+                                .clone();
+                            let label = format!("{}\n", label.as_deref().unwrap_or(url));
+                            buffer.insert_with_tags_by_name(&mut buffer.end_iter(), &label, &["p"]);
+
+                            let anchor = buffer.create_child_anchor(&mut buffer.end_iter());
+                            let entry = gtk::Entry::builder()
+                                .width_chars(40)
+                                .secondary_icon_name("mail-send-symbolic")
+                                .build();
+                            entry.connect_activate(clone!(
+                                #[weak(rename_to = this)]
+                                self,
+                                #[strong]
+                                url,
+                                move |entry| {
+                                    this.submit_prompt(&url, &entry.text());
+                                }
+                            ));
+                            entry.connect_icon_release(|entry, _| entry.emit_activate());
+                            text_view.add_child_at_anchor(&entry, &anchor);
+                        }
                         gemini::Tag::Heading(1) => {
                             let mut title = self.imp().title.borrow_mut();
                             if title.is_none() {
@@ -348,6 +451,7 @@ impl Hypertext {
                     match parent_tag {
                         gemini::Tag::Paragraph
                         | gemini::Tag::Link(_, _)
+                        | gemini::Tag::Prompt(_, _)
                         | gemini::Tag::CodeBlock
                         | gemini::Tag::Heading(_)
                         | gemini::Tag::Item => {
@@ -372,7 +476,9 @@ impl Hypertext {
                         .unwrap()
                         .text_view
                         .buffer();
-                    match parent_tag.context("Missing parent tag")? {
+                    let start = buffer.end_iter().offset();
+                    let parent_tag = parent_tag.context("Missing parent tag")?;
+                    match parent_tag {
                         gemini::Tag::CodeBlock => {
                             buffer.insert_with_tags_by_name(&mut buffer.end_iter(), text, &["pre"]);
                         }
@@ -395,6 +501,7 @@ impl Hypertext {
                         }
                         _ => buffer.insert_with_tags_by_name(&mut buffer.end_iter(), text, &["p"]),
                     }
+                    apply_direction(&buffer, start, matches!(parent_tag, gemini::Tag::CodeBlock));
                 }
                 Event::BlankLine => {
                     let buffer = self
@@ -411,6 +518,15 @@ impl Hypertext {
         }
         Ok(())
     }
+    fn submit_prompt(&self, link: &str, data: &str) {
+        match self.parse_link(link) {
+            Ok(mut url) => {
+                url.set_query(Some(data));
+                self.emit_by_name::<()>("open", &[&url.as_str()]);
+            }
+            Err(e) => log::error!("Invalid prompt url {:?}: {}", link, e),
+        }
+    }
     fn parse_link(&self, link: &str) -> Result<Url, url::ParseError> {
         let current_url = Url::parse(self.imp().url.borrow().as_str())?;
         let link_url = Url::options().base_url(Some(&current_url)).parse(link)?;
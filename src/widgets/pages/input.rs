@@ -10,6 +10,8 @@ mod imp {
         pub label: TemplateChild<gtk::Label>,
         #[template_child]
         pub entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub password_entry: TemplateChild<gtk::PasswordEntry>,
     }
 
     #[glib::object_subclass]
@@ -1,7 +1,9 @@
 mod download;
+mod editor;
 pub mod hypertext;
 mod input;
 
 pub use download::Download;
+pub use editor::Editor;
 pub use hypertext::Hypertext;
 pub use input::Input;
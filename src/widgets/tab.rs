@@ -2,14 +2,15 @@ use std::cell::{Cell, Ref, RefCell};
 use std::marker::PhantomData;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use async_fs::File;
 use futures::future::RemoteHandle;
 use futures::io::BufReader;
 use futures::prelude::*;
-use futures::task::LocalSpawnExt;
-use gemini::CertificateError;
+use futures::task::{Context as TaskContext, LocalSpawnExt, Poll};
+use gemini::{CertificateError, LineParser};
 use glib::{clone, Properties};
 use gtk::gdk::prelude::*;
 use gtk::prelude::*;
@@ -23,18 +24,62 @@ use url::Url;
 use super::pages::{self, hypertext};
 use crate::common;
 use crate::common::{glibctx, open_file_externally, open_uri_externally};
-use crate::lossy_text_read::*;
-use crate::session_provider::SessionProvider;
+use crate::response_cache::CachedPage;
+use crate::session_provider::{SessionProvider, TofuStore};
+use crate::text_decoder::TextDecoder;
 
 const BYTES_BEFORE_YIELD: usize = 1024 * 10;
 
 #[derive(Clone)]
 pub struct HistoryItem {
     pub url: url::Url,
-    pub cache: Rc<RefCell<Option<Vec<u8>>>>,
     pub scroll_progress: f64,
 }
 
+/// Details of the response displayed by a tab
+#[derive(Clone, Debug)]
+pub struct PageInfo {
+    pub url: Url,
+    pub status: gemini::Status,
+    pub meta: String,
+    /// Certificate presented by the server
+    pub certificate: Option<gtk::gio::TlsCertificate>,
+    /// Time between the request and the response header
+    pub header_time: Duration,
+    /// Size of the body, once it has been read
+    pub size: Option<usize>,
+    /// Time taken to receive and display the whole page, once it has been read
+    pub load_time: Option<Duration>,
+}
+
+/// Counts the bytes read from the inner reader
+/// Url answering the sensitive input requested at `url` with `input`, and the url without the
+/// answer shown in its place. The query of `url`, such as a previous answer, is left out of both
+fn sensitive_answer(url: &Url, input: &str) -> (Url, Url) {
+    let mut masked_url = url.clone();
+    masked_url.set_query(None);
+    let mut url = masked_url.clone();
+    url.set_query(Some(input));
+    (url, masked_url)
+}
+
+struct ByteCounter<R> {
+    inner: R,
+    count: Rc<Cell<usize>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ByteCounter<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let n = futures::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.count.set(self.count.get() + n);
+        Poll::Ready(Ok(n))
+    }
+}
+
 #[derive(Clone, Debug, glib::Boxed, Default)]
 #[boxed_type(name = "GeopardHistoryStatus")]
 pub struct HistoryStatus {
@@ -65,6 +110,9 @@ impl History {
     fn current(&self) -> Option<&HistoryItem> {
         self.index.map(|i| &self.items[i])
     }
+    fn current_mut(&mut self) -> Option<&mut HistoryItem> {
+        self.index.map(|i| &mut self.items[i])
+    }
     fn items(&self) -> &[HistoryItem] {
         &self.items
     }
@@ -96,7 +144,13 @@ pub mod imp {
         pub(crate) stack: TemplateChild<gtk::Stack>,
         #[template_child]
         pub(crate) clamp: TemplateChild<adw::ClampScrollable>,
+        #[template_child]
+        pub(crate) redirect_banner: TemplateChild<adw::Banner>,
         pub(crate) req_handle: RefCell<Option<RemoteHandle<()>>>,
+        pub(crate) page_info: RefCell<Option<PageInfo>>,
+        /// Url shown in the page info in place of the requested one, which contains the
+        /// answer to a sensitive input
+        pub(crate) masked_url: RefCell<Option<Url>>,
         #[property(get = Self::history_status)]
         pub(crate) history_status: PhantomData<HistoryStatus>,
         #[property(get, set)]
@@ -117,7 +171,10 @@ pub mod imp {
                 scroll_win: Default::default(),
                 stack: Default::default(),
                 clamp: Default::default(),
+                redirect_banner: Default::default(),
                 req_handle: Default::default(),
+                page_info: Default::default(),
+                masked_url: Default::default(),
                 history_status: PhantomData,
                 progress: Default::default(),
                 title: Default::default(),
@@ -206,21 +263,11 @@ impl Tab {
             }
         }
 
-        let body: Rc<RefCell<Option<Vec<u8>>>> = Rc::new(RefCell::new(None));
-        let body_weak = Rc::downgrade(&body);
-
         self.add_to_history(HistoryItem {
             url: url.clone(),
-            cache: body,
             scroll_progress: 0.0,
         });
-
-        let this = self.clone();
-        let fut = async move {
-            let data = this.open_url(url).await;
-            *body_weak.upgrade().unwrap().borrow_mut() = data;
-        };
-        self.spawn_request(fut);
+        self.spawn_request(self.open(url));
     }
     fn add_to_history(&self, mut item: HistoryItem) -> usize {
         let imp = self.imp();
@@ -247,11 +294,16 @@ impl Tab {
     fn spawn_request(&self, fut: impl Future<Output = ()> + 'static) {
         let imp = self.imp();
         self.clear_stack_widgets();
+        imp.redirect_banner.set_revealed(false);
+        imp.page_info.take();
+        imp.masked_url.take();
         imp.req_handle
             .replace(Some(glibctx().spawn_local_with_handle(fut).unwrap()));
     }
-    fn open_url(&self, url: Url) -> impl Future<Output = Option<Vec<u8>>> {
+    /// Loads `url`, keeping its body in the session cache if it can be cached
+    fn open_url(&self, url: Url) -> impl Future<Output = ()> {
         let imp = self.imp();
+        let session = self.session();
 
         self.set_progress(0.0);
         *imp.title.borrow_mut() = url.to_string();
@@ -261,36 +313,39 @@ impl Tab {
 
         let this = self.clone();
         let fut = async move {
-            let cache = match this.send_request(url.clone()).await {
+            match this.send_request(url.clone()).await {
                 Ok(Some(cache)) => {
                     info!("Page loaded, can be cached ({})", url.clone());
-                    Some(cache)
+                    // The page is cached under its final url, as in the history
+                    let target = Url::parse(&this.url()).unwrap_or_else(|_| url.clone());
+                    session.cache_page(&target, cache);
+                    session.cache_redirect(&url, &target);
                 }
                 Ok(_) => {
                     info!("Page loaded ({})", &url);
-                    None
                 }
                 Err(e) => {
                     this.display_error(e);
-                    None
                 }
             };
             this.set_progress(1.0);
-            cache
         };
         self.set_progress(0.3);
         fut
     }
+    /// Displays the page of `item` from the session cache, loading it if it isn't cached
     fn open_history(&self, item: HistoryItem) -> Pin<Box<dyn Future<Output = ()>>> {
-        let HistoryItem { url, cache, .. } = item;
-
-        let cache = cache.borrow();
-        match &*cache {
-            Some(cache) => Box::pin(self.open_cached(url, cache.clone())),
-            None => Box::pin(self.open_url(url).map(|_| {})),
+        self.open(item.url)
+    }
+    /// Displays `url` from the session cache, shared by all the tabs, loading it if it isn't
+    /// cached or if its body is older than the cache ttl
+    fn open(&self, url: Url) -> Pin<Box<dyn Future<Output = ()>>> {
+        match self.session().cached(&url) {
+            Some(cache) => Box::pin(self.open_cached(url, cache)),
+            None => Box::pin(self.open_url(url)),
         }
     }
-    fn open_cached(&self, url: Url, cache: Vec<u8>) -> impl Future<Output = ()> {
+    fn open_cached(&self, url: Url, cache: CachedPage) -> impl Future<Output = ()> {
         let imp = self.imp();
 
         imp.progress.set(0.0);
@@ -304,8 +359,12 @@ impl Tab {
 
         let this = self.clone();
         async move {
-            let buf = BufReader::new(&*cache);
-            let res = this.display_gemini(buf).await;
+            let buf = BufReader::new(&*cache.body);
+            // The cache is stored already decoded, only its languages are kept
+            let mime: Option<gemini::Mime> = cache
+                .lang
+                .and_then(|lang| format!("text/gemini; lang={}", lang).parse().ok());
+            let res = this.display_gemini(buf, mime.as_ref()).await;
             match res {
                 Ok(_) => {
                     info!("Loaded {} from cache", &url);
@@ -324,14 +383,44 @@ impl Tab {
     pub fn next(&self) -> bool {
         self.move_in_history(1)
     }
+    /// Loads the current page again, bypassing the session cache
     pub fn reload(&self) {
         let imp = self.imp();
 
-        if let Some(h) = imp.history.borrow_mut().current() {
-            self.spawn_request(self.open_history(h.clone()));
+        let url = imp.history.borrow().current().map(|h| h.url.clone());
+        if let Some(url) = url {
+            self.spawn_request(self.open_url(url));
         }
     }
 
+    pub fn edit_with_titan(&self) {
+        let imp = self.imp();
+
+        let Ok(mut url) = Url::parse(&self.url()) else {
+            return;
+        };
+        let content = match url.scheme() {
+            "gemini" => {
+                let current = imp.history.borrow().current().map(|item| item.url.clone());
+                // Uploading an empty editor would erase the page
+                let Some(cache) = current.and_then(|url| self.session().cached(&url)) else {
+                    self.toast("Reload the page to edit it");
+                    return;
+                };
+                url.set_scheme("titan").unwrap();
+                Some(String::from_utf8_lossy(&cache.body).into_owned())
+            }
+            "titan" => None,
+            _ => {
+                log::warn!("Can't upload {} with titan", url);
+                return;
+            }
+        };
+
+        self.clear_stack_widgets();
+        self.display_editor(url, content.as_deref());
+    }
+
     async fn open_file_url(&self, url: Url) -> Result<()> {
         let path = url
             .to_file_path()
@@ -342,16 +431,29 @@ impl Tab {
         let lines = BufReader::new(file);
         match path.extension().map(|x| x.to_str()) {
             Some(Some("gmi")) | Some(Some("gemini")) => {
-                this.display_gemini(lines).await?;
+                this.display_gemini(lines, None).await?;
             }
             _ => {
-                this.display_text(lines).await?;
+                this.display_text(lines, None).await?;
             }
         }
         Ok(())
     }
-    async fn send_request(&self, url: Url) -> Result<Option<Vec<u8>>> {
+    async fn send_request(&self, url: Url) -> Result<Option<CachedPage>> {
+        if self
+            .imp()
+            .config
+            .borrow()
+            .proxies
+            .contains_key(url.scheme())
+        {
+            return self.open_gemini_url(url).await;
+        }
         match url.scheme() {
+            "about" if url.path() == "certificates" => {
+                self.display_certificates();
+                Ok(None)
+            }
             "about" => {
                 let mut about = common::ABOUT_PAGE.to_owned();
                 use std::fmt::Write;
@@ -363,7 +465,7 @@ impl Tab {
                 )
                 .unwrap();
                 let reader = futures::io::BufReader::new(about.as_bytes());
-                self.display_gemini(reader).await?;
+                self.display_gemini(reader, None).await?;
                 Ok(None)
             }
             "file" => {
@@ -371,60 +473,180 @@ impl Tab {
                 Ok(None)
             }
             "gemini" => self.open_gemini_url(url).await,
+            "gopher" => self.open_gopher_url(url).await,
+            "spartan" => self.open_spartan_url(url).await,
+            "finger" => {
+                let body = self.session().client().fetch_finger(url.as_str()).await?;
+                self.display_text(futures::io::BufReader::new(body), None)
+                    .await?;
+                Ok(None)
+            }
+            "nex" => self.open_nex_url(url).await,
+            "titan" => {
+                self.display_editor(url, None);
+                Ok(None)
+            }
             _ => {
                 self.display_url_confirmation(&url);
                 Ok(None)
             }
         }
     }
-    async fn open_gemini_url(&self, url: Url) -> anyhow::Result<Option<Vec<u8>>> {
+    async fn open_gemini_url(&self, url: Url) -> anyhow::Result<Option<CachedPage>> {
+        let started = Instant::now();
         let res = self.session().client().fetch(url.as_str()).await;
         let res = match res {
             Ok(res) => res,
-            Err(gemini::Error::Tls(CertificateError::BadIdentity)) => {
-                self.display_mitm_error();
+            Err(gemini::Error::Tls {
+                host,
+                port,
+                error: CertificateError::BadIdentity,
+            }) => {
+                self.display_mitm_error(host, port);
                 return Ok(None);
             }
-            Err(gemini::Error::Tls(e)) => {
-                self.display_tls_error(e);
+            Err(gemini::Error::Tls { host, port, error }) => {
+                self.display_tls_error(error, host, port);
                 return Ok(None);
             }
             Err(e) => return Err(e.into()),
         };
-
+        self.display_response(url, res, started).await
+    }
+    async fn open_spartan_url(&self, url: Url) -> anyhow::Result<Option<CachedPage>> {
+        let started = Instant::now();
+        let res = self.session().client().fetch_spartan(url.as_str()).await?;
+        self.display_response(url, res, started).await
+    }
+    /// Displays `res`, the response to a request sent at `started`
+    async fn display_response(
+        &self,
+        url: Url,
+        res: gemini::Response,
+        started: Instant,
+    ) -> anyhow::Result<Option<CachedPage>> {
         use gemini::Status::*;
         let meta = res.meta().to_owned();
         let status = res.status();
+        let retry_after = res.retry_after();
         debug!("Status: {:?}", &status);
 
+        // Without a redirect, the answer to a sensitive input is only kept in the request:
+        // asking again, downloading or showing the page info uses the masked url
+        let url = match (res.redirects().first(), res.redirects().last()) {
+            (Some(first), Some(last)) => {
+                self.show_redirect(&first.url, &last.target);
+                last.target.clone()
+            }
+            _ => self.imp().masked_url.borrow().clone().unwrap_or(url),
+        };
+
+        self.imp().page_info.replace(Some(PageInfo {
+            url: url.clone(),
+            status,
+            meta: meta.clone(),
+            certificate: res.certificate().cloned(),
+            header_time: started.elapsed(),
+            size: None,
+            load_time: None,
+        }));
+        let size = Rc::new(Cell::new(0));
+
         let this = self.clone();
         let res = match status {
             Input(_) => {
-                self.display_input(url.clone(), &meta);
+                let sensitive = status.code() == gemini::StatusCode::SensitiveInput;
+                self.display_input(url.clone(), &meta, sensitive);
                 None
             }
             Success(_) => {
+                let mime = res.mime();
                 let body = res.body().context("Body not found")?;
-                let buffered = futures::io::BufReader::new(body);
-
-                if meta.contains("text/gemini") {
-                    let res = this.display_gemini(buffered).await?;
-                    Some(res)
-                } else if meta.contains("text") {
-                    self.display_text(buffered).await?;
-                    None
-                } else {
-                    self.display_download(url.clone(), buffered).await?;
-                    None
+                let buffered = futures::io::BufReader::new(ByteCounter {
+                    inner: body,
+                    count: size.clone(),
+                });
+
+                match mime {
+                    Some(mime) if mime.is_gemini() => {
+                        let body = this.display_gemini(buffered, Some(&mime)).await?;
+                        Some(CachedPage {
+                            body: body.into(),
+                            lang: mime.lang().map(str::to_owned),
+                        })
+                    }
+                    Some(mime) if mime.is_text() => {
+                        self.display_text(buffered, Some(&mime)).await?;
+                        None
+                    }
+                    _ => {
+                        self.display_download(url.clone(), buffered).await?;
+                        None
+                    }
                 }
             }
-            Redirect(_) => bail!("Redirected more than 5 times"),
-            TempFail(_) => bail!("Temporary server failure"),
-            PermFail(_) => bail!("Permanent server failure"),
-            CertRequired(_) => bail!("A certificate is required to access this page"),
+            Redirect(_) => {
+                self.display_redirect_confirmation(&url, url.join(&meta)?);
+                None
+            }
+            TempFail(_) | PermFail(_) => {
+                self.display_status_error(&url, status, &meta, retry_after);
+                None
+            }
+            CertRequired(_) => {
+                self.display_identity_required(url.clone(), status.code(), &meta);
+                None
+            }
         };
+        if let Some(info) = self.imp().page_info.borrow_mut().as_mut() {
+            info.size = Some(size.get());
+            info.load_time = Some(started.elapsed());
+        }
         Ok(res)
     }
+    /// Details of the displayed response, for the protocols having a response header
+    pub fn page_info(&self) -> Option<PageInfo> {
+        self.imp().page_info.borrow().clone()
+    }
+
+    async fn open_gopher_url(&self, url: Url) -> anyhow::Result<Option<CachedPage>> {
+        let item_type = gemini::gopher::item_type(&url);
+        if item_type == '7' && url.query().is_none() {
+            self.display_input(url, "Search", false);
+            return Ok(None);
+        }
+
+        let body = self.session().client().fetch_gopher(url.as_str()).await?;
+        let buffered = futures::io::BufReader::new(body);
+        match item_type {
+            '1' | '7' => {
+                self.display_hypertext(buffered, gemini::gopher::MenuParser::new(), None)
+                    .await?;
+            }
+            '0' | 'h' => self.display_text(buffered, None).await?,
+            _ => self.display_download(url, buffered).await?,
+        }
+        Ok(None)
+    }
+
+    async fn open_nex_url(&self, url: Url) -> anyhow::Result<Option<CachedPage>> {
+        let body = self.session().client().fetch_nex(url.as_str()).await?;
+        let buffered = futures::io::BufReader::new(body);
+
+        if gemini::nex::is_directory(&url) {
+            self.display_gemini(buffered, None).await?;
+            return Ok(None);
+        }
+        let path = std::path::Path::new(url.path());
+        match path.extension().map(|x| x.to_str()) {
+            Some(Some("gmi")) | Some(Some("gemini")) => {
+                self.display_gemini(buffered, None).await?;
+            }
+            None | Some(Some("txt")) => self.display_text(buffered, None).await?,
+            _ => self.display_download(url, buffered).await?,
+        }
+        Ok(None)
+    }
 
     fn download_path(file_name: &str) -> anyhow::Result<std::path::PathBuf> {
         let mut file_name = std::path::PathBuf::from(file_name);
@@ -518,8 +740,13 @@ impl Tab {
 
         Ok(())
     }
-    async fn display_text(&self, mut stream: impl AsyncBufRead + Unpin) -> anyhow::Result<()> {
-        let page = self.new_hypertext_page();
+    async fn display_text(
+        &self,
+        stream: impl AsyncBufRead + Unpin,
+        mime: Option<&gemini::Mime>,
+    ) -> anyhow::Result<()> {
+        let mut stream = TextDecoder::new(stream, mime.map_or("utf-8", |m| m.charset()));
+        let page = self.new_hypertext_page(mime.and_then(|m| m.lang()));
         let mut pe = Vec::new();
 
         page.render(
@@ -532,7 +759,7 @@ impl Tab {
         let mut last_yield_at_bytes = 0;
 
         loop {
-            let n = stream.read_line_lossy(&mut line).await?;
+            let n = stream.read_line(&mut line).await?;
             if n == 0 {
                 break;
             }
@@ -554,13 +781,23 @@ impl Tab {
         Ok(())
     }
 
-    fn display_input(&self, url: Url, msg: &str) {
+    fn display_input(&self, url: Url, msg: &str, sensitive: bool) {
         let imp = self.imp();
 
         let text_input = pages::Input::new();
         imp.stack.add_child(&text_input);
         imp.stack.set_visible_child(&text_input);
         text_input.imp().label.set_label(msg);
+        if sensitive {
+            text_input.imp().entry.set_visible(false);
+            text_input.imp().password_entry.set_visible(true);
+            text_input.imp().password_entry.connect_activate(clone!(
+                #[weak(rename_to = this)]
+                self,
+                move |entry| this.spawn_send_sensitive(url.clone(), &entry.text())
+            ));
+            return;
+        }
         text_input.imp().entry.connect_activate(move |entry| {
             let query = entry.text().to_string();
             let mut url = url.clone();
@@ -571,6 +808,494 @@ impl Tab {
         });
     }
 
+    /// Answers a sensitive input request. Unlike the other requests, the url containing the
+    /// answer never reaches the history, the cache or the url bar, which keep the url of the
+    /// request. Reloading asks for the input again
+    fn spawn_send_sensitive(&self, url: Url, input: &str) {
+        let (url, masked_url) = sensitive_answer(&url, input);
+
+        let this = self.clone();
+        let fut = async move {
+            this.imp().masked_url.replace(Some(masked_url));
+            if let Err(e) = this.send_request(url).await {
+                this.display_error(e);
+            }
+            this.set_progress(1.0);
+        };
+        self.set_progress(0.3);
+        self.spawn_request(fut);
+    }
+
+    fn display_editor(&self, url: Url, content: Option<&str>) {
+        let imp = self.imp();
+
+        let page = pages::Editor::new();
+        page.imp().label.set_label(url.as_str());
+        if let Some(content) = content {
+            page.imp().text_view.buffer().set_text(content);
+        }
+        imp.stack.add_child(&page);
+        imp.stack.set_visible_child(&page);
+
+        page.imp().upload_btn.connect_clicked(clone!(
+            #[weak(rename_to = this)]
+            self,
+            #[weak]
+            page,
+            move |btn| {
+                btn.set_sensitive(false);
+                let this = this.clone();
+                let page = page.clone();
+                let btn = btn.clone();
+                let url = url.clone();
+                glibctx().spawn_local(async move {
+                    if let Err(e) = this.upload_titan(&url, &page).await {
+                        page.imp().label.set_label(&e.to_string());
+                    }
+                    btn.set_sensitive(true);
+                });
+            }
+        ));
+    }
+    async fn upload_titan(&self, url: &Url, page: &pages::Editor) -> anyhow::Result<()> {
+        let imp = page.imp();
+
+        let buffer = imp.text_view.buffer();
+        let body = buffer.text(&buffer.start_iter(), &buffer.end_iter(), false);
+        let mime = imp.mime_entry.text();
+        let token = imp.token_entry.text();
+        let token = (!token.is_empty()).then_some(token.as_str());
+
+        let res = self
+            .session()
+            .client()
+            .upload(url.as_str(), &mime, token, body.as_bytes())
+            .await?;
+
+        use gemini::Status::*;
+        let mut target = match res.status() {
+            Redirect(_) => url.join(res.meta())?,
+            Success(_) => url.clone(),
+            _ => bail!("Upload failed: {}", res.meta()),
+        };
+        if target.scheme() == "titan" {
+            target.set_scheme("gemini").unwrap();
+        }
+        self.session().uncache_page(&target);
+        self.spawn_open_url(target);
+        Ok(())
+    }
+    fn display_identity_required(&self, url: Url, code: gemini::StatusCode, msg: &str) {
+        let imp = self.imp();
+
+        let p = adw::StatusPage::new();
+        p.set_title(match code {
+            gemini::StatusCode::CertificateNotAuthorized => "Certificate Not Authorized",
+            gemini::StatusCode::CertificateNotValid => "Certificate Not Valid",
+            _ => "Certificate Required",
+        });
+        p.set_description(Some(&glib::markup_escape_text(msg)));
+        p.set_icon_name(Some("dialog-password-symbolic"));
+
+        let child = gtk::Box::new(gtk::Orientation::Vertical, 12);
+        child.set_halign(gtk::Align::Center);
+
+        let page_only = gtk::CheckButton::with_label("Use only for this page");
+        page_only.set_halign(gtk::Align::Center);
+
+        let names: Vec<String> = self
+            .session()
+            .identities()
+            .borrow()
+            .identities()
+            .map(|identity| identity.name().to_owned())
+            .collect();
+        if !names.is_empty() {
+            let row = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+            let dropdown = gtk::DropDown::from_strings(
+                &names.iter().map(|name| name.as_str()).collect::<Vec<_>>(),
+            );
+            row.append(&dropdown);
+
+            let use_btn = gtk::Button::with_label("Use Identity");
+            use_btn.add_css_class("suggested-action");
+            use_btn.add_css_class("pill");
+            use_btn.connect_clicked(clone!(
+                #[weak(rename_to = this)]
+                self,
+                #[weak]
+                dropdown,
+                #[weak]
+                page_only,
+                #[strong]
+                url,
+                move |_| {
+                    let name = &names[dropdown.selected() as usize];
+                    if let Err(e) = this.use_identity(&url, name, page_only.is_active()) {
+                        this.display_error(e);
+                    }
+                }
+            ));
+            row.append(&use_btn);
+            child.append(&row);
+        }
+
+        let row = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        let entry = gtk::Entry::builder()
+            .placeholder_text("New identity name")
+            .build();
+        row.append(&entry);
+
+        let create_btn = gtk::Button::with_label("Create Identity");
+        create_btn.add_css_class("pill");
+        create_btn.connect_clicked(clone!(
+            #[weak(rename_to = this)]
+            self,
+            #[weak]
+            entry,
+            #[weak]
+            page_only,
+            #[strong]
+            url,
+            move |_| {
+                let name = entry.text();
+                let created = this
+                    .session()
+                    .identities()
+                    .borrow_mut()
+                    .create(&name)
+                    .map(|_| ());
+                let res = created
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| this.use_identity(&url, &name, page_only.is_active()));
+                if let Err(e) = res {
+                    this.display_error(e);
+                }
+            }
+        ));
+        row.append(&create_btn);
+        child.append(&row);
+        child.append(&page_only);
+
+        p.set_child(Some(&child));
+
+        imp.stack.add_child(&p);
+        imp.stack.set_visible_child(&p);
+    }
+    fn use_identity(&self, url: &Url, name: &str, page_only: bool) -> anyhow::Result<()> {
+        let scope = if page_only {
+            gemini::identity::page_scope(url)
+        } else {
+            gemini::identity::host_scope(url).context("The url doesn't have a host")?
+        };
+        self.session()
+            .identities()
+            .borrow_mut()
+            .add_scope(&scope, name)?;
+        self.reload();
+        Ok(())
+    }
+
+    /// Points the tab to the url reached by following redirects, telling where it came from
+    fn show_redirect(&self, from: &Url, to: &Url) {
+        let imp = self.imp();
+
+        if let Some(item) = imp.history.borrow_mut().current_mut() {
+            item.url = to.clone();
+        }
+        *imp.title.borrow_mut() = to.to_string();
+        self.notify_title();
+        *imp.url.borrow_mut() = to.to_string();
+        self.notify_url();
+
+        // The query is left out, it may be the answer to a sensitive input
+        let mut from = from.clone();
+        from.set_query(None);
+        imp.redirect_banner
+            .set_title(&format!("Redirected from {}", from));
+        imp.redirect_banner.set_button_label(Some("Copy New Url"));
+        imp.redirect_banner
+            .set_action_name(Some("win.set-clipboard"));
+        imp.redirect_banner
+            .set_action_target_value(Some(&to.as_str().to_variant()));
+        imp.redirect_banner.set_revealed(true);
+    }
+    fn display_redirect_confirmation(&self, from: &Url, to: Url) {
+        let imp = self.imp();
+        let mut from = from.clone();
+        from.set_query(None);
+
+        let status_page = adw::StatusPage::new();
+        if from.scheme() != to.scheme() {
+            status_page.set_title("Redirect to Another Protocol");
+        } else {
+            status_page.set_title("Redirect to Another Host");
+        }
+        status_page.set_description(Some(&glib::markup_escape_text(&format!(
+            "{} redirects to {}",
+            from, to
+        ))));
+        status_page.set_icon_name(Some("dialog-warning-symbolic"));
+
+        let button = gtk::Button::with_label("Follow Redirect");
+        button.add_css_class("suggested-action");
+        button.add_css_class("pill");
+        button.set_halign(gtk::Align::Center);
+        button.connect_clicked(clone!(
+            #[weak(rename_to = this)]
+            self,
+            move |_| this.spawn_open_url(to.clone())
+        ));
+        status_page.set_child(Some(&button));
+
+        imp.stack.add_child(&status_page);
+        imp.stack.set_visible_child(&status_page);
+    }
+    /// Lists the known hosts and the hosts trusted for this session, with the actions to
+    /// manage them
+    fn display_certificates(&self) {
+        let imp = self.imp();
+        let validator = self.session().validator().clone();
+
+        let button = |icon: &str, tooltip: &str| {
+            let button = gtk::Button::from_icon_name(icon);
+            button.set_tooltip_text(Some(tooltip));
+            button.set_valign(gtk::Align::Center);
+            button.add_css_class("flat");
+            button
+        };
+
+        let page = adw::PreferencesPage::new();
+
+        let overridden = adw::PreferencesGroup::builder()
+            .title("Trusted Despite Errors")
+            .description("Certificates whose errors are ignored, for this session or longer")
+            .build();
+        let overridden_hosts = validator.overridden_hosts();
+        for key in &overridden_hosts {
+            let row = adw::ActionRow::builder()
+                .title(glib::markup_escape_text(key))
+                .subtitle("Until Geopard is closed")
+                .build();
+            let forget = button("user-trash-symbolic", "Stop Trusting");
+            forget.connect_clicked(clone!(
+                #[weak(rename_to = this)]
+                self,
+                #[strong]
+                validator,
+                #[strong]
+                key,
+                move |_| {
+                    validator.remove_override(&key);
+                    this.reload();
+                }
+            ));
+            row.add_suffix(&forget);
+            overridden.add(&row);
+        }
+        let persistent_overrides = validator.persistent_overrides();
+        for (key, trust_override) in &persistent_overrides {
+            let until = match trust_override.until {
+                Some(until) => format!("Until {}", common::format_time(until)),
+                None => "Always".to_owned(),
+            };
+            let row = adw::ActionRow::builder()
+                .title(glib::markup_escape_text(key))
+                .subtitle(glib::markup_escape_text(&format!(
+                    "SHA-256 {}\n{}",
+                    trust_override.fingerprint, until
+                )))
+                .subtitle_selectable(true)
+                .build();
+            let forget = button("user-trash-symbolic", "Stop Trusting");
+            forget.connect_clicked(clone!(
+                #[weak(rename_to = this)]
+                self,
+                #[strong]
+                validator,
+                #[strong]
+                key,
+                move |_| {
+                    validator.remove_persistent_override(&key);
+                    this.reload();
+                }
+            ));
+            row.add_suffix(&forget);
+            overridden.add(&row);
+        }
+        if !overridden_hosts.is_empty() || !persistent_overrides.is_empty() {
+            page.add(&overridden);
+        }
+
+        let known = adw::PreferencesGroup::builder()
+            .title("Known Hosts")
+            .description(
+                "Certificates trusted the first time each host was visited. \
+                A pinned certificate is never replaced by a renewed one",
+            )
+            .build();
+        let mut known_hosts: Vec<_> = validator.known_hosts().into_iter().collect();
+        known_hosts.sort_by(|(a, _), (b, _)| a.cmp(b));
+        if known_hosts.is_empty() {
+            known.add(&adw::ActionRow::builder().title("No known hosts").build());
+        }
+        for (key, host) in known_hosts {
+            let mut subtitle = format!(
+                "SHA-256 {}\nFirst seen {}",
+                host.fingerprint,
+                common::format_time(host.first_seen)
+            );
+            if let Some(expires) = host.expires {
+                subtitle.push_str(&format!(", expires {}", common::format_time(expires)));
+            }
+            let row = adw::ActionRow::builder()
+                .title(glib::markup_escape_text(&key))
+                .subtitle(glib::markup_escape_text(&subtitle))
+                .subtitle_selectable(true)
+                .build();
+
+            let pin = gtk::ToggleButton::builder()
+                .icon_name("view-pin-symbolic")
+                .tooltip_text("Pin Certificate")
+                .valign(gtk::Align::Center)
+                .active(host.pinned)
+                .css_classes(vec!["flat"])
+                .build();
+            pin.connect_toggled(clone!(
+                #[strong]
+                validator,
+                #[strong]
+                key,
+                move |pin| validator.set_pinned(&key, pin.is_active())
+            ));
+            row.add_suffix(&pin);
+
+            let retrust = button("view-refresh-symbolic", "Trust the Current Certificate");
+            retrust.connect_clicked(clone!(
+                #[weak(rename_to = this)]
+                self,
+                #[strong]
+                validator,
+                #[strong]
+                key,
+                move |_| {
+                    validator.forget(&key);
+                    // Visiting the host stores the certificate it presents now
+                    match Url::parse(&format!("gemini://{}/", key)) {
+                        Ok(url) => this.spawn_open_url(url),
+                        Err(e) => log::error!("Invalid known host {}: {}", key, e),
+                    }
+                }
+            ));
+            row.add_suffix(&retrust);
+
+            let forget = button("user-trash-symbolic", "Forget Certificate");
+            forget.connect_clicked(clone!(
+                #[weak(rename_to = this)]
+                self,
+                #[strong]
+                validator,
+                #[strong]
+                key,
+                move |_| {
+                    validator.forget(&key);
+                    this.reload();
+                }
+            ));
+            row.add_suffix(&forget);
+            known.add(&row);
+        }
+        page.add(&known);
+
+        let other_clients = adw::PreferencesGroup::builder()
+            .title("Other Clients")
+            .description("Hosts trusted by other gemini clients are added to the known hosts")
+            .build();
+        for &store in TofuStore::ALL {
+            let row = adw::ActionRow::builder()
+                .title(store.name())
+                .subtitle(store.file_name())
+                .build();
+            let import = button("document-open-symbolic", "Import");
+            import.connect_clicked(clone!(
+                #[weak(rename_to = this)]
+                self,
+                move |_| this.spawn_import_known_hosts(store)
+            ));
+            row.add_suffix(&import);
+            if store.can_export() {
+                let export = button("document-save-symbolic", "Export");
+                export.connect_clicked(clone!(
+                    #[weak(rename_to = this)]
+                    self,
+                    move |_| this.spawn_export_known_hosts(store)
+                ));
+                row.add_suffix(&export);
+            }
+            other_clients.add(&row);
+        }
+        page.add(&other_clients);
+
+        imp.stack.add_child(&page);
+        imp.stack.set_visible_child(&page);
+    }
+    fn toast(&self, title: &str) {
+        if let Some(overlay) = self
+            .ancestor(adw::ToastOverlay::static_type())
+            .and_downcast::<adw::ToastOverlay>()
+        {
+            overlay.add_toast(adw::Toast::new(title));
+        }
+    }
+    fn spawn_import_known_hosts(&self, store: TofuStore) {
+        let dialog = gtk::FileDialog::builder()
+            .title(format!("Import the {} Known Hosts", store.name()))
+            .build();
+        let window = self.root().and_downcast::<gtk::Window>();
+        let this = self.clone();
+        glibctx().spawn_local(async move {
+            // The dialog was cancelled
+            let Ok(file) = dialog.open_future(window.as_ref()).await else {
+                return;
+            };
+            let Some(path) = file.path() else {
+                return;
+            };
+            match this.session().validator().import(store, &path) {
+                Ok(added) => {
+                    this.toast(&format!("{} hosts imported", added));
+                    this.reload();
+                }
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    this.toast(&format!("Import failed: {}", e));
+                }
+            }
+        });
+    }
+    fn spawn_export_known_hosts(&self, store: TofuStore) {
+        let dialog = gtk::FileDialog::builder()
+            .title(format!("Export the Known Hosts for {}", store.name()))
+            .initial_name(store.file_name())
+            .build();
+        let window = self.root().and_downcast::<gtk::Window>();
+        let this = self.clone();
+        glibctx().spawn_local(async move {
+            let Ok(file) = dialog.save_future(window.as_ref()).await else {
+                return;
+            };
+            let Some(path) = file.path() else {
+                return;
+            };
+            match this.session().validator().export(store, &path) {
+                Ok(()) => this.toast("Known hosts exported"),
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    this.toast(&format!("Export failed: {}", e));
+                }
+            }
+        });
+    }
     fn display_url_confirmation(&self, url: &Url) {
         let imp = self.imp();
         let status_page = adw::StatusPage::new();
@@ -603,10 +1328,10 @@ impl Tab {
         imp.stack.add_child(&status_page);
         imp.stack.set_visible_child(&status_page);
     }
-    fn new_hypertext_page(&self) -> pages::Hypertext {
+    fn new_hypertext_page(&self, lang: Option<&str>) -> pages::Hypertext {
         let imp = self.imp();
 
-        let surface = pages::hypertext::Surface::new(imp.config.borrow().clone());
+        let surface = pages::hypertext::Surface::new(imp.config.borrow().clone(), lang);
         imp.clamp.set_child(Some(surface.root()));
 
         let p = pages::Hypertext::new(self.url(), surface);
@@ -633,22 +1358,33 @@ impl Tab {
         );
         p
     }
+    /// Renders a gemini page. Without a `mime`, the text is read as utf-8
     async fn display_gemini<T: AsyncBufRead + Unpin>(
         &self,
-        mut reader: T,
+        reader: T,
+        mime: Option<&gemini::Mime>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.display_hypertext(reader, gemini::Parser::new(), mime)
+            .await
+    }
+    async fn display_hypertext<T: AsyncBufRead + Unpin>(
+        &self,
+        reader: T,
+        mut parser: impl LineParser,
+        mime: Option<&gemini::Mime>,
     ) -> anyhow::Result<Vec<u8>> {
         let imp = self.imp();
+        let mut reader = TextDecoder::new(reader, mime.map_or("utf-8", |m| m.charset()));
 
-        let mut parser = gemini::Parser::new();
         let mut data = String::with_capacity(1024);
         let mut total = 0;
         let mut last_yield_at_bytes = 0;
 
-        let page = self.new_hypertext_page();
+        let page = self.new_hypertext_page(mime.and_then(|m| m.lang()));
         let mut page_events = vec![];
 
         loop {
-            let res = reader.read_line_lossy(&mut data).await;
+            let res = reader.read_line(&mut data).await;
 
             let n = match res {
                 Ok(0) => break,
@@ -686,6 +1422,65 @@ impl Tab {
 
         Ok(data.into_bytes())
     }
+    fn display_status_error(
+        &self,
+        url: &Url,
+        status: gemini::Status,
+        meta: &str,
+        retry_after: Option<std::time::Duration>,
+    ) {
+        use gemini::StatusCode::*;
+        let imp = self.imp();
+
+        let title = match status.code() {
+            ServerUnavailable => "Server Unavailable",
+            CgiError => "Server Script Error",
+            ProxyError => "Proxy Error",
+            SlowDown => "Slow Down",
+            NotFound => "Page Not Found",
+            Gone => "Page Gone",
+            ProxyRequestRefused => "Proxy Request Refused",
+            BadRequest => "Bad Request",
+            _ => match status {
+                gemini::Status::TempFail(_) => "Temporary Failure",
+                _ => "Permanent Failure",
+            },
+        };
+        let description = match retry_after {
+            Some(delay) => format!(
+                "The server is receiving too many requests. Retrying in {} seconds",
+                delay.as_secs()
+            ),
+            None => meta.to_owned(),
+        };
+
+        let p = adw::StatusPage::new();
+        p.set_title(title);
+        if !description.is_empty() {
+            p.set_description(Some(&glib::markup_escape_text(&description)));
+        }
+        p.set_icon_name(Some("dialog-error-symbolic"));
+
+        imp.stack.add_child(&p);
+        imp.stack.set_visible_child(&p);
+
+        if let Some(delay) = retry_after {
+            let url = url.clone();
+            glib::timeout_add_local_once(
+                delay,
+                clone!(
+                    #[weak(rename_to = this)]
+                    self,
+                    move || {
+                        // The user may have opened another page in the meantime
+                        if this.url() == url.as_str() {
+                            this.reload();
+                        }
+                    }
+                ),
+            );
+        }
+    }
     pub fn display_error(&self, error: anyhow::Error) {
         let imp = self.imp();
 
@@ -699,7 +1494,9 @@ impl Tab {
         imp.stack.add_child(&p);
         imp.stack.set_visible_child(&p);
     }
-    pub fn display_mitm_error(&self) {
+    /// Displays the change of the certificate of `host:port`, the server that presented it,
+    /// which is the proxy for the proxied urls
+    pub fn display_mitm_error(&self, host: String, port: u16) {
         let imp = self.imp();
 
         let p = adw::StatusPage::new();
@@ -714,11 +1511,7 @@ impl Tab {
             #[weak(rename_to = this)]
             self,
             move |_| {
-                let url = Url::parse(&this.url()).unwrap();
-
-                this.session()
-                    .validator()
-                    .remove_known(url.host_str().unwrap());
+                this.session().validator().remove_known(&host, port);
                 this.reload();
             }
         ));
@@ -730,36 +1523,47 @@ impl Tab {
         imp.stack.add_child(&p);
         imp.stack.set_visible_child(&p);
     }
-    pub fn display_tls_error(&self, error: CertificateError) {
+    /// Displays the error of the certificate of `host:port`, the server that presented it
+    pub fn display_tls_error(&self, error: CertificateError, host: String, port: u16) {
         let imp = self.imp();
 
         let p = adw::StatusPage::new();
         p.set_title("Tls Server Certificate Error");
         p.set_description(Some(&format!(
-            "{:?}
-        You can override the certificate validity for this session
-        and continue",
-            &error.to_string()
+            "{}. You can trust this certificate anyway, for this session or longer, and continue",
+            error
         )));
         p.set_icon_name(Some("dialog-error-symbolic"));
 
-        let override_btn = gtk::Button::with_label("Continue");
-        override_btn.connect_clicked(clone!(
-            #[weak(rename_to = this)]
-            self,
-            move |_| {
-                let url = Url::parse(&this.url()).unwrap();
-
-                this.session()
-                    .validator()
-                    .override_trust(url.host_str().unwrap());
-                this.reload();
-            }
-        ));
-        override_btn.set_halign(gtk::Align::Center);
-        override_btn.add_css_class("destructive-action");
-        override_btn.add_css_class("pill");
-        p.set_child(Some(&override_btn));
+        let buttons = gtk::Box::new(gtk::Orientation::Vertical, 12);
+        buttons.set_halign(gtk::Align::Center);
+        // `days` is None to trust the certificate for this session, Some(None) to always trust it
+        let trust_button = |label: &str, days: Option<Option<i64>>| {
+            let button = gtk::Button::with_label(label);
+            button.connect_clicked(clone!(
+                #[weak(rename_to = this)]
+                self,
+                #[strong]
+                host,
+                move |_| {
+                    let session = this.session();
+                    let validator = session.validator();
+                    let persisted =
+                        days.is_some_and(|days| validator.persist_override(&host, port, days));
+                    if !persisted {
+                        validator.override_trust(&host, port);
+                    }
+                    this.reload();
+                }
+            ));
+            button.add_css_class("pill");
+            buttons.append(&button);
+            button
+        };
+        trust_button("Continue", None).add_css_class("destructive-action");
+        trust_button("Trust for 30 Days", Some(Some(30)));
+        trust_button("Always Trust", Some(None));
+        p.set_child(Some(&buttons));
 
         imp.stack.add_child(&p);
         imp.stack.set_visible_child(&p);
@@ -786,3 +1590,23 @@ impl Tab {
         moved
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensitive_reask() {
+        let url = Url::parse("gemini://example.org/login").unwrap();
+        let (request, masked) = sensitive_answer(&url, "1234");
+        assert_eq!(request.as_str(), "gemini://example.org/login?1234");
+        assert_eq!(masked, url);
+
+        // A wrong answer is asked again at the masked url, or at the url of the request
+        for url in [&masked, &request] {
+            let (request, masked) = sensitive_answer(url, "5678");
+            assert_eq!(request.as_str(), "gemini://example.org/login?5678");
+            assert_eq!(masked.as_str(), "gemini://example.org/login");
+        }
+    }
+}
@@ -16,6 +16,7 @@ use url::Url;
 
 use crate::common::{bookmarks_url, glibctx, BOOKMARK_FILE_PATH};
 use crate::session_provider::SessionProvider;
+use crate::widgets::page_info::page_info_dialog;
 use crate::widgets::tab::{HistoryItem, HistoryStatus, Tab};
 use crate::{build_config, config};
 
@@ -190,6 +191,7 @@ impl Window {
             .property("application", app)
             .build();
         let imp = this.imp();
+        imp.session_provider.set_config(&config);
         imp.config.replace(config);
         imp.zoom.borrow_mut().value = 1.0;
 
@@ -253,6 +255,12 @@ impl Window {
             a("bookmark-current")
                 .activate(move |this: &Window, _, _| this.bookmark_current())
                 .build(),
+            a("edit-titan")
+                .activate(move |this: &Window, _, _| this.edit_titan())
+                .build(),
+            a("page-info")
+                .activate(move |this: &Window, _, _| this.present_page_info())
+                .build(),
             a("close-tab")
                 .activate(move |this: &Window, _, _| this.close_tab())
                 .build(),
@@ -709,6 +717,21 @@ impl Window {
     fn reload(&self) {
         self.current_tab().reload();
     }
+    fn edit_titan(&self) {
+        self.current_tab().edit_with_titan();
+    }
+    fn present_page_info(&self) {
+        let imp = self.imp();
+        match self.current_tab().page_info() {
+            Some(info) => {
+                let validator = imp.session_provider.validator();
+                page_info_dialog(&info, &validator).present(Some(self));
+            }
+            None => imp
+                .toast_overlay
+                .add_toast(adw::Toast::new("No information available for this page")),
+        }
+    }
     fn bookmark_current(&self) {
         let imp = self.imp();
         let url = imp.url_bar.text().to_string();